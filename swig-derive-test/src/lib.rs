@@ -1,38 +1,570 @@
-#![feature(proc_macro)]
-#![feature(proc_macro_gen)]
 
 #[macro_use]
 extern crate swig_derive;
 use swig_derive::{swiggen, swiggen_hack};
+use std::ops::{Add, Mul, Neg, Sub};
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-#[derive(Default, Swig)]
-#[swig_derive(Default)]
+#[cfg(not(any(feature = "safe_handles", feature = "borrow_checking")))]
+swiggen_prelude!(async);
+#[cfg(all(feature = "safe_handles", not(feature = "borrow_checking")))]
+swiggen_prelude!(async, safe_handles);
+#[cfg(all(feature = "borrow_checking", not(feature = "safe_handles")))]
+swiggen_prelude!(async, borrow_checking);
+#[cfg(all(feature = "safe_handles", feature = "borrow_checking"))]
+swiggen_prelude!(async, safe_handles, borrow_checking);
+
+/// A simple struct to test the bindings with.
+#[derive(Default, Clone, Debug, PartialEq, Eq, Hash, Swig)]
+#[swig_derive(Default, Clone, Debug, Display, PartialEq, Eq, Hash)]
 pub struct Test {
+    /// Shown as "Test(field)" by `str()`. Docs can contain anything, even `%}`.
     pub field: u32
 }
 
+impl std::fmt::Display for Test {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Test({})", self.field)
+    }
+}
+
 swiggen_hack!{
 impl Test {
+    #[swiggen(Test)]
+    pub const MAX_FIELD: u32 = 1 << 16;
+
     #[swiggen(Test)]
     pub fn new(field: u32) -> Self {
         Self {
-            field: field,
+            field,
         }
     }
 
+    #[swiggen(Test)]
+    #[swig(constructor)]
+    pub fn from_half(half: u16) -> Self {
+        Self::new(u32::from(half) * 2)
+    }
+
+    /// Get the value of the field.
     #[swiggen(Test)]
     pub fn get_field(&self) -> u32 {
         self.field
     }
+
+    #[swiggen(Test)]
+    pub fn set_field(&mut self, field: u32) {
+        self.field = field;
+    }
+
+    #[swiggen(Test)]
+    pub fn kind(&self) -> &str {
+        "test"
+    }
+
+    #[swiggen(Test)]
+    pub fn repeat(&self, times: u32) -> std::vec::IntoIter<Self> {
+        (0..times).map(|_| Test::new(self.field)).collect::<Vec<_>>().into_iter()
+    }
 }
 }
 
+#[swiggen]
+pub const MAX_RETRIES: u32 = 5;
+
+#[swiggen]
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[swiggen]
+#[allow(clippy::approx_constant)]
+pub static PI_ISH: f64 = 3.14;
+
+#[swiggen(Test)]
+impl From<u32> for Test {
+    #[swig(name = "from_u32")]
+    fn from(field: u32) -> Self {
+        Test::new(field)
+    }
+}
+
+/// Anything with a name.
+pub trait Named {
+    fn name(&self) -> String;
+}
+
+#[swiggen(Test)]
+impl Named for Test {
+    fn name(&self) -> String {
+        format!("test {}", self.field)
+    }
+}
+
 #[swiggen]
 pub fn different_test() -> Test {
     Test::new(42)
 }
 
+/// Takes a `Test` by reference, leaving it with the caller.
+#[swiggen]
+pub fn double_field(test: &Test) -> u32 {
+    test.field * 2
+}
+
+#[swiggen]
+pub fn version() -> &'static str {
+    "0.1.0"
+}
+
+/// Sum of the squares below `n`, slowly. Other Python threads keep running.
+#[swiggen]
+#[swig(release_gil)]
+pub fn sum_squares(n: u64) -> u64 {
+    (0..n).fold(0, |acc, i| acc.wrapping_add(i * i))
+}
+
+/// Sleep for `ms` milliseconds. Other Python threads keep running.
+#[swiggen]
+#[swig(release_gil)]
+pub fn pause(ms: u64) {
+    std::thread::sleep(std::time::Duration::from_millis(ms));
+}
+
+/// Greet someone `times` times, or once by default.
+#[swiggen]
+#[swig(default(greeting = "Hello"))]
+pub fn greet(name: &str, greeting: &str, times: Option<u32>) -> String {
+    vec![format!("{}, {}!", greeting, name); times.unwrap_or(1) as usize].join(" ")
+}
+
+#[swiggen]
+pub fn reverse_bytes(data: &[u8]) -> Vec<u8> {
+    data.iter().rev().cloned().collect()
+}
+
+#[swiggen]
+pub fn describe_test(field: u32) -> (u32, String, Test) {
+    (field * 2, format!("Test({})", field), Test::new(field))
+}
+
+#[swiggen(instantiate(T = u32, T = f64))]
+pub fn largest<T: PartialOrd + Copy>(a: T, b: T) -> T {
+    if a > b { a } else { b }
+}
+
+#[derive(Default, Swig)]
+#[swig_derive(Default)]
+#[swig_instantiate(T = u32)]
+pub struct Stack<T> {
+    items: Vec<T>,
+}
+
+swiggen_hack!{
+#[allow(clippy::len_without_is_empty)]
+impl<T: Copy> Stack<T> {
+    #[swiggen(Stack, instantiate(T = u32))]
+    pub fn push(&mut self, item: T) {
+        self.items.push(item);
+    }
+
+    #[swiggen(Stack, instantiate(T = u32))]
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    #[swiggen(Stack, instantiate(T = u32))]
+    pub fn last_or(&self, fallback: T) -> T {
+        self.items.last().cloned().unwrap_or(fallback)
+    }
+}
+}
+
+#[derive(Default, Swig)]
+#[swig_derive(Default)]
+#[swig_instantiate(T = u32)]
+#[swig(name = "IntQueue")]
+pub struct Queue<T> {
+    items: VecDeque<T>,
+}
+
+swiggen_hack!{
+#[swiggen(Queue, instantiate(T = u32))]
+#[allow(clippy::len_without_is_empty)]
+impl<T: Copy> Queue<T> {
+    pub fn push(&mut self, item: T) {
+        self.items.push_back(item);
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn first_or(&self, fallback: T) -> T {
+        self.first().unwrap_or(fallback)
+    }
+
+    #[swig(name = "pop_or")]
+    pub fn pop_front_or(&mut self, fallback: T) -> T {
+        self.items.pop_front().unwrap_or(fallback)
+    }
+
+    #[swig(skip)]
+    pub fn items_mut(&mut self) -> &mut VecDeque<T> {
+        &mut self.items
+    }
+
+    fn first(&self) -> Option<T> {
+        self.items.front().cloned()
+    }
+}
+}
+
+#[derive(Default, Swig)]
+#[swig_derive(Default)]
+#[swig_instantiate((K = u32, V = f64))]
+pub struct Entry<K, V> {
+    key: K,
+    value: V,
+}
+
+swiggen_hack!{
+impl<K: Copy, V: Copy> Entry<K, V> {
+    #[swiggen(Entry, instantiate((V = f64, K = u32)))]
+    pub fn set(&mut self, key: K, value: V) {
+        self.key = key;
+        self.value = value;
+    }
+
+    #[swiggen(Entry, instantiate((V = f64, K = u32)))]
+    pub fn value(&self) -> V {
+        self.value
+    }
+}
+}
+
+/// Scores values, and can be implemented in the target language.
+#[swiggen]
+pub trait Scorer {
+    /// The score for `x`, higher is better.
+    fn score(&self, x: f64) -> f64;
+}
+
+#[swiggen]
+pub fn best_score(scorer: &dyn Scorer, a: f64, b: f64) -> f64 {
+    scorer.score(a).max(scorer.score(b))
+}
+
+#[derive(Swig)]
+pub struct Judge {
+    scorer: Box<dyn Scorer>,
+}
+
+swiggen_hack!{
+impl Judge {
+    #[swiggen(Judge)]
+    pub fn new(scorer: Box<dyn Scorer>) -> Self {
+        Self {
+            scorer,
+        }
+    }
+
+    #[swiggen(Judge)]
+    pub fn judge(&self, x: f64) -> f64 {
+        self.scorer.score(x)
+    }
+}
+}
+
+#[swiggen]
+pub fn count_matching(limit: u32, pred: impl Fn(u32) -> bool) -> u32 {
+    (0..limit).filter(|&x| pred(x)).count() as u32
+}
+
+#[derive(Default, Swig)]
+#[swig_derive(Default)]
+pub struct Ticker {
+    count: u32,
+    listeners: Vec<Box<dyn Fn(u32)>>,
+}
+
+swiggen_hack!{
+impl Ticker {
+    #[swiggen(Ticker)]
+    pub fn subscribe(&mut self, listener: Box<dyn Fn(u32)>) {
+        self.listeners.push(listener);
+    }
+
+    #[swiggen(Ticker)]
+    pub fn tick(&mut self) -> u32 {
+        self.count += 1;
+        for listener in &self.listeners {
+            listener(self.count);
+        }
+        self.count
+    }
+}
+}
+
+#[swiggen]
+pub fn count_up(limit: u32) -> impl Iterator<Item = u32> {
+    0..limit
+}
+
+#[swiggen]
+pub fn words(text: &str) -> std::vec::IntoIter<String> {
+    text.split_whitespace().map(String::from).collect::<Vec<_>>().into_iter()
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd, Swig)]
+#[swig_derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct Money {
+    cents: i64,
+}
+
+swiggen_hack!{
+impl Money {
+    #[swiggen(Money)]
+    pub fn new(cents: i64) -> Self {
+        Self {
+            cents,
+        }
+    }
+
+    #[swiggen(Money)]
+    pub fn cents(&self) -> i64 {
+        self.cents
+    }
+
+    #[swiggen(Money)]
+    pub fn from_dollars(dollars: f64) -> Money {
+        Money::new((dollars * 100.0).round() as i64)
+    }
+
+    #[swiggen(Money)]
+    pub fn currency() -> &'static str {
+        "USD"
+    }
+}
+}
+
+#[swiggen]
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        Money::new(self.cents + other.cents)
+    }
+}
+
+#[swiggen]
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        Money::new(self.cents - other.cents)
+    }
+}
+
+#[swiggen]
+impl Mul<i64> for Money {
+    type Output = Money;
+
+    fn mul(self, factor: i64) -> Money {
+        Money::new(self.cents * factor)
+    }
+}
+
+#[swiggen]
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money::new(-self.cents)
+    }
+}
+
+/// A counter, shared by every handle to it.
+#[derive(Default, Swig)]
+pub struct Counter {
+    count: AtomicUsize,
+}
+
+swiggen_hack!{
+#[swiggen(Counter)]
+impl Counter {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Counter::default())
+    }
+
+    pub fn bump(&self) -> usize {
+        self.count.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    pub fn reset(&mut self) {
+        *self.count.get_mut() = 0;
+    }
+}
+}
+
+/// Hands out the same counter every time.
+#[derive(Default, Swig)]
+#[swig_derive(Default)]
+pub struct Registry {
+    counter: Arc<Counter>,
+    owner: Test,
+}
+
+swiggen_hack!{
+#[swiggen(Registry)]
+impl Registry {
+    pub fn counter(&self) -> Arc<Counter> {
+        self.counter.clone()
+    }
+
+    pub fn owner(&self) -> &Test {
+        &self.owner
+    }
+
+    pub fn owner_mut(&mut self) -> &mut Test {
+        &mut self.owner
+    }
+}
+}
+
+/// Holds an `Rc`, so is only used from the thread which created it.
+#[derive(Default, Swig)]
+#[swig_derive(Default)]
+pub struct Tally {
+    count: Rc<Cell<u32>>,
+}
+
+swiggen_hack!{
+#[swiggen(Tally)]
+impl Tally {
+    pub fn add(&self) -> u32 {
+        self.count.set(self.count.get() + 1);
+        self.count.get()
+    }
+}
+}
+
+/// Counts the calls made from the thread which created it, so is only used
+/// from that thread, even though it is `Send` and `Sync`.
+#[derive(Default, Swig)]
+#[swig(unsendable)]
+#[swig_derive(Default)]
+pub struct Session {
+    calls: AtomicUsize,
+}
+
+swiggen_hack!{
+#[swiggen(Session)]
+impl Session {
+    pub fn call(&self) -> usize {
+        self.calls.fetch_add(1, Ordering::SeqCst) + 1
+    }
+}
+}
+
+static RESOURCES_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts how often it is dropped, to check freed objects are released.
+#[derive(Default, Swig)]
+#[swig_derive(Default)]
+pub struct Resource;
+
+impl Drop for Resource {
+    fn drop(&mut self) {
+        RESOURCES_DROPPED.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// How many `Resource`s have been dropped.
+#[swiggen]
+pub fn resources_dropped() -> usize {
+    RESOURCES_DROPPED.load(Ordering::SeqCst)
+}
+
+/// Values looked up by key, which may take a while.
+#[derive(Default, Swig)]
+#[swig_derive(Default)]
+pub struct Store {
+    values: HashMap<String, u32>,
+}
+
+swiggen_hack!{
+#[swiggen(Store)]
+impl Store {
+    pub fn insert(&mut self, key: &str, value: u32) {
+        self.values.insert(key.to_string(), value);
+    }
+
+    /// The value of `key`, blocking until it is found.
+    pub async fn fetch(&self, key: &str) -> u32 {
+        self.lookup(key).await
+    }
+
+    /// The value of `key`, awaited from Python.
+    #[swig(awaitable)]
+    pub async fn fetch_later(&self, key: &str) -> u32 {
+        self.lookup(key).await
+    }
+
+    async fn lookup(&self, key: &str) -> u32 {
+        self.values.get(key).cloned().unwrap_or(0)
+    }
+}
+}
+
+// Releasing the GIL in a method needs its borrows to be checked.
+#[cfg(feature = "borrow_checking")]
+swiggen_hack!{
+#[swiggen(Store)]
+impl Store {
+    /// The value of `key`, after sleeping for `ms` milliseconds. Other
+    /// Python threads keep running, but can't change the store meanwhile.
+    #[swig(release_gil)]
+    pub fn fetch_slowly(&self, key: &str, ms: u64) -> u32 {
+        std::thread::sleep(std::time::Duration::from_millis(ms));
+        self.values.get(key).cloned().unwrap_or(0)
+    }
+}
+}
+
 #[no_mangle]
 pub extern "C" fn manual_extern() -> u32 {
     Test::new(13).get_field()
 }
+
+/// Exported from a nested module, so in the `geometry` namespace.
+pub mod geometry {
+    use super::*;
+
+    #[derive(Clone, Swig)]
+    #[swig_derive(Clone)]
+    pub struct Point {
+        x: f64,
+        y: f64,
+    }
+
+    swiggen_hack!{
+    #[swiggen(Point)]
+    impl Point {
+        pub fn new(x: f64, y: f64) -> Self {
+            Point { x, y }
+        }
+
+        pub fn norm(&self) -> f64 {
+            (self.x * self.x + self.y * self.y).sqrt()
+        }
+    }
+    }
+
+    #[swiggen]
+    pub fn unit_x() -> Point {
+        Point::new(1.0, 0.0)
+    }
+}
@@ -0,0 +1,160 @@
+# Type stubs for the `swig_derive_test` module, generated by swiggen.
+from typing import Any, Awaitable, Callable, Iterator, List, Optional, Tuple, overload
+
+MAX_RETRIES: int
+
+VERSION: str
+
+PI_ISH: float
+
+def different_test() -> Test: ...
+
+def double_field(test: Test) -> int: ...
+
+def version() -> str: ...
+
+def sum_squares(n: int) -> int: ...
+
+def pause(ms: int) -> None: ...
+
+def greet(name: str, greeting: str = ..., times: Optional[int] = ...) -> str: ...
+
+def reverse_bytes(data: bytes) -> bytes: ...
+
+def describe_test(field: int) -> Tuple[int, str, Test]: ...
+
+def largest_u32(a: int, b: int) -> int: ...
+
+def largest_f64(a: float, b: float) -> float: ...
+
+def best_score(scorer: Scorer, a: float, b: float) -> float: ...
+
+def count_matching(limit: int, pred: Callable[[int], bool]) -> int: ...
+
+def count_up(limit: int) -> Iterator[int]: ...
+
+def words(text: str) -> Iterator[str]: ...
+
+def resources_dropped() -> int: ...
+
+def unit_x() -> Point: ...
+
+def free_string(s: str) -> None: ...
+
+def free_bytes(ptr: Any, len: int) -> None: ...
+
+def swig_last_error_kind() -> int: ...
+
+def swig_take_error() -> str: ...
+
+def swig_raise_error(msg: str) -> None: ...
+
+def manual_extern() -> int: ...
+
+class Test:
+    @overload
+    def __init__(self) -> None: ...
+    @overload
+    def __init__(self, field: int) -> None: ...
+    @overload
+    def __init__(self, *, half: int) -> None: ...
+    def __copy__(self) -> Test: ...
+    def __deepcopy__(self, memo: Any) -> Test: ...
+    def __repr__(self) -> str: ...
+    def __str__(self) -> str: ...
+    def __eq__(self, other: object) -> bool: ...
+    def __ne__(self, other: object) -> bool: ...
+    def __hash__(self) -> int: ...
+    MAX_FIELD: int
+    def get_field(self) -> int: ...
+    def set_field(self, field: int) -> None: ...
+    def kind(self) -> str: ...
+    def repeat(self, times: int) -> Iterator[Test]: ...
+    @staticmethod
+    def from_u32(field: int) -> Test: ...
+    def name(self) -> str: ...
+
+class StackU32:
+    def __init__(self) -> None: ...
+    def push(self, item: int) -> None: ...
+    def len(self) -> int: ...
+    def last_or(self, fallback: int) -> int: ...
+
+class IntQueue:
+    def __init__(self) -> None: ...
+    def push(self, item: int) -> None: ...
+    def len(self) -> int: ...
+    def first_or(self, fallback: int) -> int: ...
+    def pop_or(self, fallback: int) -> int: ...
+
+class EntryU32F64:
+    def __init__(self) -> None: ...
+    def set(self, key: int, value: float) -> None: ...
+    def value(self) -> float: ...
+
+class Scorer:
+    def score(self, x: float) -> float: ...
+
+class Judge:
+    def __init__(self, scorer: Scorer) -> None: ...
+    def judge(self, x: float) -> float: ...
+
+class Ticker:
+    def __init__(self) -> None: ...
+    def subscribe(self, listener: Callable[[int], None]) -> None: ...
+    def tick(self) -> int: ...
+
+class Money:
+    def __copy__(self) -> Money: ...
+    def __deepcopy__(self, memo: Any) -> Money: ...
+    def __repr__(self) -> str: ...
+    def __eq__(self, other: object) -> bool: ...
+    def __ne__(self, other: object) -> bool: ...
+    def __lt__(self, other: Money) -> bool: ...
+    def __le__(self, other: Money) -> bool: ...
+    def __gt__(self, other: Money) -> bool: ...
+    def __ge__(self, other: Money) -> bool: ...
+    def __init__(self, cents: int) -> None: ...
+    def cents(self) -> int: ...
+    @staticmethod
+    def from_dollars(dollars: float) -> Money: ...
+    @staticmethod
+    def currency() -> str: ...
+    def __add__(self, other: Money) -> Money: ...
+    def __sub__(self, other: Money) -> Money: ...
+    def __mul__(self, other: int) -> Money: ...
+    def __neg__(self) -> Money: ...
+
+class Counter:
+    def __init__(self) -> None: ...
+    def bump(self) -> int: ...
+    def reset(self) -> None: ...
+
+class Registry:
+    def __init__(self) -> None: ...
+    def counter(self) -> Counter: ...
+    def owner(self) -> Test: ...
+    def owner_mut(self) -> Test: ...
+
+class Tally:
+    def __init__(self) -> None: ...
+    def add(self) -> int: ...
+
+class Session:
+    def __init__(self) -> None: ...
+    def call(self) -> int: ...
+
+class Resource:
+    def __init__(self) -> None: ...
+
+class Store:
+    def __init__(self) -> None: ...
+    def insert(self, key: str, value: int) -> None: ...
+    def fetch(self, key: str) -> int: ...
+    def fetch_later(self, key: str) -> Awaitable[int]: ...
+
+class Point:
+    def __copy__(self) -> Point: ...
+    def __deepcopy__(self, memo: Any) -> Point: ...
+    def __init__(self, x: float, y: float) -> None: ...
+    def norm(self) -> float: ...
@@ -5,9 +5,217 @@ assert t.get_field() == 0
 
 t = sdt.Test(12)
 assert t.get_field() == 12
+assert "test the bindings" in sdt.Test.__doc__
+assert 'field: Shown as "Test(field)"' in sdt.Test.__doc__ and "`%}`" in sdt.Test.__doc__
+assert "value of the field" in sdt.Test.get_field.__doc__
+assert sdt.Test(field=12).get_field() == 12
+assert sdt.Test(half=6).get_field() == 12
 
 assert sdt.different_test().get_field() == 42
+assert sdt.double_field(t) == 24 and t.get_field() == 12
+try:
+    sdt.double_field(None)
+    assert False, "borrowed `None` as a `Test`"
+except RuntimeError:
+    pass
+
+import copy
+c = copy.copy(t)
+assert c.get_field() == 12 and c == t and c is not t
+assert copy.deepcopy(t) == t
+assert t != sdt.different_test()
+assert str(t) == "Test(12)"
+assert repr(t) == "Test { field: 12 }"
+assert hash(t) == hash(c)
+
+assert t.kind() == "test"
+assert sdt.version() == "0.1.0"
+
+assert sdt.greet("Bob") == "Hello, Bob!"
+assert "or once by default" in sdt.greet.__doc__
+assert sdt.greet("Bob", times=2) == "Hello, Bob! Hello, Bob!"
+assert sdt.greet(greeting="Hi", name="Bob", times=None) == "Hi, Bob!"
+try:
+    sdt.greet("Bob", times=2**32)
+    assert False, "wrapped an optional `u32`"
+except OverflowError:
+    pass
+
+assert sdt.reverse_bytes(b"\x00\x01\xff") == b"\xff\x01\x00"
+assert sdt.reverse_bytes(bytearray(b"abc")) == b"cba"
+
+doubled, desc, test = sdt.describe_test(7)
+assert (doubled, desc, test.get_field()) == (14, "Test(7)", 7)
+
+assert sdt.largest_u32(3, 5) == 5
+assert sdt.largest_f64(2.5, -1.0) == 2.5
+
+s = sdt.StackU32()
+assert s.last_or(7) == 7
+s.push(1)
+s.push(2)
+assert (s.len(), s.last_or(7)) == (2, 2)
+
+q = sdt.IntQueue()
+assert q.first_or(7) == 7
+q.push(1)
+q.push(2)
+assert (q.len(), q.first_or(7)) == (2, 1)
+assert (q.pop_or(7), q.len()) == (1, 1)
+assert not hasattr(q, "items_mut") and not hasattr(q, "first")
+
+e = sdt.EntryU32F64()
+e.set(3, 1.5)
+assert e.value() == 1.5
+
+class Doubler(sdt.Scorer):
+    def score(self, x):
+        return 2 * x
+
+assert sdt.best_score(Doubler(), 1.5, 3.0) == 6.0
+j = sdt.Judge(Doubler())
+assert j.judge(4.0) == 8.0
+
+class Failing(sdt.Scorer):
+    def score(self, x):
+        raise ValueError("no score for %s" % x)
+
+try:
+    sdt.best_score(Failing(), 1.5, 3.0)
+    assert False, "ignored the exception of an override"
+except RuntimeError as e:
+    assert "no score for" in str(e)
+
+assert sdt.count_matching(10, lambda x: x % 2 == 0) == 5
+
+ticks = []
+ticker = sdt.Ticker()
+ticker.subscribe(ticks.append)
+ticker.subscribe(lambda n: ticks.append(-n))
+ticker.tick()
+ticker.tick()
+assert ticks == [1, -1, 2, -2]
+
+assert list(sdt.count_up(4)) == [0, 1, 2, 3]
+assert list(sdt.words("lazy iterators  work")) == ["lazy", "iterators", "work"]
+assert [r.get_field() for r in t.repeat(3)] == [12, 12, 12]
+
+a, b = sdt.Money(150), sdt.Money(25)
+assert (a + b).cents() == 175
+assert (a - b).cents() == 125
+assert (b * 3).cents() == 75
+assert (-a).cents() == -150
+assert b < a and b <= a and a > b and a >= b and a == sdt.Money(150)
+assert sorted([a, b]) == [b, a]
+assert sdt.Money.from_dollars(1.25).cents() == 125
+assert sdt.Money.currency() == "USD"
 
 assert sdt.manual_extern() == 13
 
+registry = sdt.Registry()
+registry.counter().bump()
+assert registry.counter().bump() == 2
+counter = sdt.Counter()
+del registry
+assert counter.bump() == 1
+try:
+    counter.reset()
+    assert False, "mutated a shared counter"
+except RuntimeError:
+    pass
+
+import gc
+import weakref
+registry = sdt.Registry()
+alive = weakref.ref(registry)
+owner = registry.owner()
+del registry
+gc.collect()
+assert alive() is not None
+assert owner.get_field() == 0
+try:
+    owner.set_field(1)
+    assert False, "mutated through a `&` view"
+except RuntimeError:
+    pass
+del owner
+gc.collect()
+assert alive() is None
+registry = sdt.Registry()
+registry.owner_mut().set_field(3)
+assert registry.owner().get_field() == 3
+
+import threading
+def raises_elsewhere(f):
+    errors = []
+    def call():
+        try:
+            f()
+        except RuntimeError as e:
+            errors.append(e)
+    thread = threading.Thread(target=call)
+    thread.start()
+    thread.join()
+    return len(errors) == 1
+
+tally = sdt.Tally()
+assert tally.add() == 1
+assert raises_elsewhere(tally.add)
+assert tally.add() == 2
+
+# Marked `#[swig(unsendable)]`, so bound to its thread like `Tally`.
+dropped = sdt.resources_dropped()
+resource = sdt.Resource()
+del resource
+assert sdt.resources_dropped() == dropped + 1
+
+session = sdt.Session()
+assert session.call() == 1
+assert raises_elsewhere(session.call)
+assert session.call() == 2
+
+assert sdt.sum_squares(1000) == 332833500
+# The pauses overlap, as the GIL is released while in Rust.
+import time
+start = time.monotonic()
+threads = [threading.Thread(target=sdt.pause, args=(200,)) for _ in range(4)]
+for thread in threads:
+    thread.start()
+for thread in threads:
+    thread.join()
+assert time.monotonic() - start < 0.6
+
+store = sdt.Store()
+store.insert("a", 1)
+assert store.fetch("a") == 1 and store.fetch("b") == 0
+import asyncio
+async def fetch_both():
+    return await asyncio.gather(store.fetch_later("a"), store.fetch_later("b"))
+assert asyncio.run(fetch_both()) == [1, 0]
+try:
+    store.fetch_later("a")
+    assert False, "awaitable outside of an event loop"
+except RuntimeError:
+    pass
+
+assert sdt.MAX_RETRIES == 5
+assert sdt.VERSION == "0.1.0"
+assert sdt.PI_ISH == 3.14
+assert sdt.Test.MAX_FIELD == sdt.Test_MAX_FIELD == 65536
+assert sdt.Test.from_u32(7).name() == "test 7"
+
+# `make layouts` runs this for each layout of nested modules.
+import os
+layout = os.environ.get("SWIGGEN_PYTHON_MODULES", "flat")
+if layout == "flat":
+    Point, unit_x = sdt.Point, sdt.unit_x
+elif layout == "prefix":
+    Point, unit_x = sdt.geometry_Point, sdt.geometry_unit_x
+    assert not hasattr(sdt, "Point")
+else:
+    from swig_derive_test.geometry import Point, unit_x
+    assert Point is sdt.geometry.Point is sdt.Point
+assert Point(3.0, 4.0).norm() == 5.0
+assert unit_x().norm() == Point(0.0, 1.0).norm() == 1.0
+
 print("It works!")
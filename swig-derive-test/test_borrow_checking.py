@@ -0,0 +1,51 @@
+# Run by `make preludes`, against bindings built with `borrow_checking`.
+import swig_derive_test as sdt
+
+def raises(f, error=sdt.BorrowError):
+    try:
+        f()
+    except error:
+        return True
+    return False
+
+# A view mutably borrows its parent for as long as it is alive.
+registry = sdt.Registry()
+owner = registry.owner_mut()
+assert raises(registry.owner)
+assert raises(registry.owner_mut)
+del owner
+assert registry.owner().get_field() == 0
+
+# A view through `&` allows other shared borrows, but no mutation.
+owner = registry.owner()
+assert registry.owner().get_field() == 0
+assert raises(registry.owner_mut)
+del owner
+registry.owner_mut().set_field(2)
+assert registry.owner().get_field() == 2
+
+# Another thread keeps running while `fetch_slowly` has released the GIL, but
+# can't change the store it borrows.
+import threading
+import time
+store = sdt.Store()
+store.insert("a", 1)
+fetched = []
+thread = threading.Thread(target=lambda: fetched.append(store.fetch_slowly("a", 300)))
+thread.start()
+time.sleep(0.1)
+assert fetched == []
+assert raises(lambda: store.insert("a", 2))
+thread.join()
+assert fetched == [1]
+store.insert("a", 2)
+assert store.fetch_slowly("a", 0) == 2
+
+# Without a running event loop, an awaitable raises before borrowing anything.
+assert raises(lambda: store.fetch_later("a"), RuntimeError)
+store.insert("a", 3)
+
+# A callback can't mutate the object which is calling it.
+ticker = sdt.Ticker()
+ticker.subscribe(lambda n: ticker.tick())
+assert raises(ticker.tick, RuntimeError)
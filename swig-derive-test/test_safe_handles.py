@@ -0,0 +1,37 @@
+# Run by `make preludes`, against bindings built with `safe_handles`.
+import ctypes
+import gc
+
+import swig_derive_test as sdt
+
+lib = ctypes.CDLL(sdt._swig_derive_test.__file__)
+lib.__SWIG_INJECT_free_Test.argtypes = [ctypes.c_void_p]
+
+def handle(obj):
+    # The wrapper class starts with the handle of its Rust object.
+    return ctypes.c_void_p.from_address(int(obj.this)).value
+
+def raises(f):
+    try:
+        f()
+    except RuntimeError:
+        return True
+    return False
+
+# Freeing the object behind the wrapper's back leaves it with a stale handle,
+# which is freed again once the wrapper is deleted. The slot is reused, but
+# with a new generation.
+t = sdt.Test(5)
+lib.__SWIG_INJECT_free_Test(handle(t))
+assert sdt.Test(7).get_field() == 7
+assert raises(t.get_field)
+assert raises(lambda: sdt._swig_derive_test.delete_Test(t))
+
+# A view is invalidated along with its parent, once nothing keeps it alive.
+registry = sdt.Registry()
+owner = registry.owner()
+assert owner.get_field() == 0
+del owner._swig_owner
+del registry
+gc.collect()
+assert raises(owner.get_field)
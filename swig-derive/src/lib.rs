@@ -1,15 +1,14 @@
 #![crate_type = "proc-macro"]
-#![feature(proc_macro)]
-#![feature(proc_macro_lib)]
-#![recursion_limit="128"]
+#![recursion_limit="512"]
 
-/// Procedural macros to generate `extern "C"` functions and SWIG wrapper code
-/// from Rust code.
+//! Procedural macros to generate `extern "C"` functions and SWIG wrapper code
+//! from Rust code.
 
 extern crate cbindgen;
 #[macro_use]
 extern crate quote;
 extern crate proc_macro;
+extern crate proc_macro2;
 extern crate syn;
 extern crate swiggen;
 
@@ -18,8 +17,14 @@ use proc_macro::TokenStream;
 /// Generate SWIG wrapper code for a struct, to handle freeing of the memory
 /// on destruction.
 /// Uses the `#[swig_derive(Foo)]` attribute to also derive these methods
-/// in SWIG. (Currently only `Default` is supported).
-#[proc_macro_derive(Swig, attributes(swig_derive))]
+/// in SWIG. Supports `Default`, `Clone`, `Debug`, `Display`, `PartialEq`,
+/// `Eq` and `Hash`.
+/// Generic types are exported once per `#[swig_instantiate(T = u32, ..)]`
+/// instance, as classes named like `FooU32`.
+/// `#[swig(name = "Bar")]` renames the class in the target language.
+/// Objects of types which aren't `Send` and `Sync`, or are marked
+/// `#[swig(unsendable)]`, can only be used from the thread creating them.
+#[proc_macro_derive(Swig, attributes(swig, swig_derive, swig_instantiate))]
 pub fn swig_it(input: TokenStream) -> TokenStream {
     // Parse the string representation
     let ast: syn::DeriveInput = syn::parse(input).unwrap();
@@ -29,19 +34,108 @@ pub fn swig_it(input: TokenStream) -> TokenStream {
 /// Convert a Rust method into an `extern "C"` definition with SWIG wrapping
 /// code. If this is used on a method inside an impl block, an additional
 /// parameter needs to be entered like `#[swiggen(Foo)]` to give the context.
+/// Generic functions list the types to export with
+/// `#[swiggen(instantiate(T = u32, T = f64))]`.
 /// Currently, the `swiggen_hack` macro needs to also wrap the impl block
 /// to make it work
+///
+/// Further options are given with `#[swig(..)]`:
+/// `#[swig(constructor)]` exports an associated function as an extra
+/// constructor of the class, and `#[swig(default(port = 8080))]` gives
+/// default values for parameters. Trailing `Option` parameters default to
+/// `None`. `#[swig(name = "load")]` renames the function in the target
+/// language, and `#[swig(skip)]` leaves it out. `#[swig(release_gil)]` lets
+/// other Python threads run while the function is in Rust.
+///
+/// An `async fn` blocks on its future, unless marked `#[swig(awaitable)]`,
+/// which returns an `asyncio` future in Python instead.
+///
+/// On a trait, this generates a C vtable and an adapter implementing the
+/// trait over it, so the trait can be implemented in the target language
+/// (using SWIG directors) and passed in as `Box<dyn Foo>` or `&dyn Foo`.
+///
+/// On a `const` or immutable `static` item of primitive or `&str` type,
+/// this exports it as a constant. Associated consts are exported from
+/// within `swiggen_hack!` as `Foo_NAME`, and as `Foo.NAME` in Python.
+///
+/// On an impl of an operator trait (`impl Add for Foo`), this exports the
+/// operator as a method of the wrapper class, e.g. `operator+`. Other trait
+/// impls are exported with `#[swiggen(Foo)]`, adding all their methods to
+/// the class (as `<Foo as Trait>::method`).
 #[proc_macro_attribute]
 pub fn swiggen(arg: TokenStream, input: TokenStream) -> TokenStream {
-    let ast: syn::ItemFn = syn::parse(input).unwrap();
+    let item: syn::Item = syn::parse(input).unwrap();
     // Parses the arg `(Foo)` as `Some(Foo)`.
     let arg: swiggen::Args = syn::parse(arg).unwrap();
-    let base_name: Option<syn::Ident> = arg.0;
+    // syn keeps an `async fn` as a verbatim item.
+    let async_fn = match item {
+        syn::Item::Verbatim(ref iv) => swiggen::parse_async_fn(&iv.tts),
+        _ => None,
+    };
+    let asyncness = async_fn.is_some();
+    let mut ast = match async_fn.map(syn::Item::Fn).unwrap_or(item) {
+        syn::Item::Fn(ast) => ast,
+        syn::Item::Trait(ast) => {
+            let new_trait = swiggen::impl_extern_trait(&ast);
+            let tokens = quote!{
+                #ast
+
+                #new_trait
+            };
+            return tokens.into();
+        },
+        syn::Item::Impl(ast) => {
+            if arg.base.is_some() {
+                return swiggen::impl_extern_impl(&arg, &ast).into();
+            }
+            let new_op = swiggen::impl_extern_op(&ast);
+            let tokens = quote!{
+                #ast
+
+                #new_op
+            };
+            return tokens.into();
+        },
+        syn::Item::Const(mut ast) => {
+            let new_const = swiggen::impl_extern_const(None, None, &ast.ident, &ast.ty, &ast.attrs);
+            ast.attrs.retain(|a| !swiggen::is_swig_attr(a));
+            let tokens = quote!{
+                #ast
 
-    let new_meth = swiggen::impl_extern_fn(&base_name, &ast);
+                #new_const
+            };
+            return tokens.into();
+        },
+        syn::Item::Static(mut ast) => {
+            if ast.mutability.is_some() {
+                panic!("Cannot export `static mut {}`, only immutable statics", ast.ident);
+            }
+            let new_const = swiggen::impl_extern_const(None, None, &ast.ident, &ast.ty, &ast.attrs);
+            ast.attrs.retain(|a| !swiggen::is_swig_attr(a));
+            let tokens = quote!{
+                #ast
+
+                #new_const
+            };
+            return tokens.into();
+        },
+        _ => panic!("`#[swiggen]` can only be used on functions, consts, statics, traits and trait impls"),
+    };
+    let new_meth = if swiggen::SwigOptions::from_attrs(&ast.attrs).skip {
+        quote!{}
+    } else {
+        swiggen::impl_extern_fn(&arg, &ast, asyncness)
+    };
+    // `#[swig(..)]` options are only meaningful to us.
+    ast.attrs.retain(|a| !swiggen::is_swig_attr(a));
+    let ast = if asyncness {
+        swiggen::add_async(&ast)
+    } else {
+        quote!{#ast}
+    };
     // When there is a base name, we rely on the `swiggen_hack`
     // to put the tokens in the right place later.
-    let tokens = if base_name.is_some() {
+    let tokens = if arg.base.is_some() {
         quote!{
             #ast
         }
@@ -57,65 +151,1399 @@ pub fn swiggen(arg: TokenStream, input: TokenStream) -> TokenStream {
 
 /// Proc macro to be used on an impl block so that any `#[swiggen]` function
 /// can generate the extern code outside of the impl block.
+/// Marking the impl block itself with `#[swiggen(Foo)]` exports all of its
+/// public methods and associated consts, except those with `#[swig(skip)]`.
 #[proc_macro]
 pub fn swiggen_hack(input: TokenStream) -> TokenStream {
     let ast: syn::ItemImpl = syn::parse(input).unwrap();
     swiggen::split_out_externs(&ast).into()
 }
 
+/// Runtime support for the generated externs, needed once in each crate.
+/// Options are given as a comma separated list:
+/// `swiggen_prelude!(safe_handles)` passes objects across as generational
+/// handles instead of raw pointers, so using a freed object is an error in
+/// the target language rather than undefined behaviour.
+/// `swiggen_prelude!(borrow_checking)` tracks the borrows of each object
+/// like a `RefCell`, raising a `BorrowError` rather than creating a `&mut`
+/// which aliases another reference.
+/// `swiggen_prelude!(async)` adds the executor thread running the futures
+/// of `#[swig(awaitable)]` functions.
 #[proc_macro]
-pub fn swiggen_prelude(_input: TokenStream) -> TokenStream {
-    // let ast: syn::ItemImpl = syn::parse(input).unwrap();
-    // swiggen::split_out_externs(&ast).into()
+pub fn swiggen_prelude(input: TokenStream) -> TokenStream {
+    let mut handles = raw_handles();
+    let mut borrows = unchecked_borrows();
+    let mut executor = no_executor();
+    for opt in input.to_string().split(',').map(|o| o.trim()).filter(|o| !o.is_empty()) {
+        match opt {
+            "safe_handles" => handles = safe_handles(),
+            "borrow_checking" => borrows = checked_borrows(),
+            "async" => executor = async_executor(),
+            _ => panic!("Unknown option `{}` for `swiggen_prelude!`, expected `safe_handles`, `borrow_checking` or `async`", opt),
+        }
+    }
+    let Handles { runtime, ref_arms, box_arms } = handles;
+    let tasks = async_runtime();
     let tokens = quote! {
         extern crate libc;
         use libc::*;
         use std::ffi::{CString, CStr};
 
         #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
         pub extern fn free_string(s: *mut c_char) {
             unsafe {
                 if s.is_null() { return }
-                CString::from_raw(s)
+                drop(CString::from_raw(s));
+            }
+        }
+
+        /// Free a byte buffer previously returned from Rust.
+        #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
+        pub extern fn free_bytes(ptr: *mut u8, len: usize) {
+            unsafe {
+                if ptr.is_null() { return }
+                drop(Box::from_raw(::std::slice::from_raw_parts_mut(ptr, len) as *mut [u8]));
+            }
+        }
+
+        thread_local! {
+            static SWIG_LAST_ERROR: ::std::cell::RefCell<Option<CString>> = ::std::cell::RefCell::new(None);
+            static SWIG_LAST_ERROR_KIND: ::std::cell::Cell<u32> = ::std::cell::Cell::new(SWIG_ERROR_RUNTIME);
+        }
+
+        /// The kinds of error, raised as different exceptions where the
+        /// target language supports it.
+        const SWIG_ERROR_RUNTIME: u32 = 0;
+        #[allow(dead_code)]
+        const SWIG_ERROR_BORROW: u32 = 1;
+
+        /// Record an error to be raised in the target language once the
+        /// current extern call returns.
+        #[allow(dead_code)]
+        fn swig_set_error<E: ::std::fmt::Display>(err: E) {
+            swig_set_error_kind(SWIG_ERROR_RUNTIME, err);
+        }
+
+        fn swig_set_error_kind<E: ::std::fmt::Display>(kind: u32, err: E) {
+            let msg = CString::new(err.to_string().replace('\0', ""))
+                .expect("NUL bytes were removed");
+            SWIG_LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+            SWIG_LAST_ERROR_KIND.with(|k| k.set(kind));
+        }
+
+        /// The kind of the last recorded error, to be checked before taking it.
+        #[no_mangle]
+        pub extern fn swig_last_error_kind() -> u32 {
+            SWIG_LAST_ERROR_KIND.with(|k| k.get())
+        }
+
+        /// The value returned from an extern when it fails before calling
+        /// into Rust, with the error raised once it returns.
+        #[allow(dead_code)]
+        trait SwigErrorValue {
+            fn swig_error_value() -> Self;
+        }
+
+        macro_rules! swig_error_values {
+            ($($ty:ty),*) => ($(
+                impl SwigErrorValue for $ty {
+                    fn swig_error_value() -> Self {
+                        Default::default()
+                    }
+                }
+            )*);
+        }
+
+        swig_error_values!((), bool, i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, f32, f64);
+
+        impl<T> SwigErrorValue for *mut T {
+            fn swig_error_value() -> Self {
+                ::std::ptr::null_mut()
+            }
+        }
+
+        impl<T> SwigErrorValue for *const T {
+            fn swig_error_value() -> Self {
+                ::std::ptr::null()
+            }
+        }
+
+        /// Define `fn $name()`, locking a global `$ty` which is created on
+        /// first use.
+        macro_rules! swig_global {
+            ($name:ident: $ty:ty) => (
+                #[allow(dead_code)]
+                fn $name() -> ::std::sync::MutexGuard<'static, $ty> {
+                    static INIT: ::std::sync::Once = ::std::sync::Once::new();
+                    static mut GLOBAL: *const ::std::sync::Mutex<$ty> = ::std::ptr::null();
+                    unsafe {
+                        INIT.call_once(|| {
+                            GLOBAL = Box::into_raw(Box::new(::std::sync::Mutex::new(Default::default())));
+                        });
+                        (*GLOBAL).lock().unwrap_or_else(|e| e.into_inner())
+                    }
+                }
+            );
+        }
+
+        /// Marks a type whose objects are only used from the thread which
+        /// created them, even though it is `Send` and `Sync`.
+        /// Implemented for types with `#[swig(unsendable)]`.
+        #[allow(dead_code)]
+        trait SwigUnsendable {}
+
+        /// Whether objects of `T` are bound to the thread which created
+        /// them is picked by the first of the `SwigThread*` traits which
+        /// applies, going from `&&SwigThreadProbe<T>` down to the probe
+        /// itself. So this needs to know the concrete type, and the answer is
+        /// a constant which `ffi_thread!` checks before touching the table of
+        /// threads, so types which are `Send` and `Sync` never lock it.
+        struct SwigThreadProbe<T>(::std::marker::PhantomData<T>);
+
+        #[allow(dead_code)]
+        fn swig_thread_probe<T>(_: *const T) -> SwigThreadProbe<T> {
+            SwigThreadProbe(::std::marker::PhantomData)
+        }
+
+        trait SwigThreadDeclared {
+            #[inline(always)]
+            fn swig_thread_bound(&self) -> bool { true }
+        }
+
+        impl<'a, 'b, T: SwigUnsendable> SwigThreadDeclared for &'a &'b SwigThreadProbe<T> {}
+
+        trait SwigThreadSafe {
+            #[inline(always)]
+            fn swig_thread_bound(&self) -> bool { false }
+        }
+
+        impl<'a, T: Send + Sync> SwigThreadSafe for &'a SwigThreadProbe<T> {}
+
+        trait SwigThreadUnsafe {
+            #[inline(always)]
+            fn swig_thread_bound(&self) -> bool { true }
+        }
+
+        impl<T> SwigThreadUnsafe for SwigThreadProbe<T> {}
+
+        /// The creating thread of each object bound to one, by its type and
+        /// address, with the number of times it was handed out.
+        type SwigThreads = ::std::collections::HashMap<(::std::any::TypeId, usize), (::std::thread::ThreadId, usize)>;
+
+        swig_global!(swig_threads: SwigThreads);
+
+        #[allow(dead_code)]
+        fn swig_thread_created<T: 'static>(ptr: *const T) {
+            if ptr.is_null() {
+                return;
+            }
+            let mut threads = swig_threads();
+            let entry = threads.entry((::std::any::TypeId::of::<T>(), ptr as usize))
+                .or_insert((::std::thread::current().id(), 0));
+            entry.1 += 1;
+        }
+
+        #[allow(dead_code)]
+        fn swig_thread_released<T: 'static>(ptr: *const T) {
+            let key = (::std::any::TypeId::of::<T>(), ptr as usize);
+            let mut threads = swig_threads();
+            let done = match threads.get_mut(&key) {
+                Some(entry) => {
+                    entry.1 -= 1;
+                    entry.1 == 0
+                },
+                None => false,
+            };
+            if done {
+                threads.remove(&key);
+            }
+        }
+
+        /// Whether the object can be used from this thread, recording an
+        /// error if not.
+        #[allow(dead_code)]
+        fn swig_thread_check<T: 'static>(ptr: *const T) -> bool {
+            let key = (::std::any::TypeId::of::<T>(), ptr as usize);
+            let thread = swig_threads().get(&key).map(|entry| entry.0);
+            match thread {
+                Some(id) if id != ::std::thread::current().id() => {
+                    swig_set_error("object can only be used from the thread which created it");
+                    false
+                },
+                _ => true,
+            }
+        }
+
+        #[allow(unused_macros)]
+        macro_rules! ffi_thread {
+            (@created $ptr:expr) => ({
+                let ptr: *const _ = $ptr;
+                if (&&&swig_thread_probe(ptr)).swig_thread_bound() {
+                    swig_thread_created(ptr);
+                }
+            });
+            (@released $ptr:expr) => ({
+                let ptr: *const _ = $ptr;
+                if (&&&swig_thread_probe(ptr)).swig_thread_bound() {
+                    swig_thread_released(ptr);
+                }
+            });
+            (@check $ptr:expr) => ({
+                let ptr: *const _ = $ptr;
+                if (&&&swig_thread_probe(ptr)).swig_thread_bound() && !swig_thread_check(ptr) {
+                    return SwigErrorValue::swig_error_value();
+                }
+            });
+        }
+
+        #borrows
+
+        /// Take the last recorded error, if any. Ownership of the string is
+        /// passed to the caller, which frees it with `free_string`.
+        #[no_mangle]
+        pub extern fn swig_take_error() -> *mut c_char {
+            SWIG_LAST_ERROR.with(|e| {
+                e.borrow_mut().take().map_or(::std::ptr::null_mut(), |s| s.into_raw())
+            })
+        }
+
+        /// Record an exception raised by the target language in a callback,
+        /// so it is raised again once control returns from Rust.
+        #[no_mangle]
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
+        pub extern fn swig_raise_error(msg: *const c_char) {
+            let msg = unsafe {
+                assert!(!msg.is_null());
+                CStr::from_ptr(msg)
             };
+            swig_set_error(msg.to_string_lossy());
+        }
+
+        /// Owns the `user_data` of a closure passed in from the target
+        /// language, releasing it with `drop` once the closure is dropped.
+        #[allow(dead_code)]
+        struct SwigCallbackGuard {
+            user_data: *mut ::std::os::raw::c_void,
+            drop: extern "C" fn(*mut ::std::os::raw::c_void),
+        }
+
+        // The target language glue is responsible for locking as needed.
+        unsafe impl Send for SwigCallbackGuard {}
+        unsafe impl Sync for SwigCallbackGuard {}
+
+        impl SwigCallbackGuard {
+            #[allow(dead_code)]
+            fn user_data(&self) -> *mut ::std::os::raw::c_void {
+                self.user_data
+            }
+        }
+
+        impl Drop for SwigCallbackGuard {
+            fn drop(&mut self) {
+                (self.drop)(self.user_data)
+            }
         }
 
+        #tasks
+
+        #executor
+
+        #runtime
+
         #[allow(unused_macros)]
         macro_rules! ffi_ref {
-            ($name:ident) => (
+            #ref_arms
+            // Strings which aren't UTF-8 raise an error, as panics can't
+            // unwind across the extern.
+            (@str $name:ident) => (
                 let $name = unsafe {
                     assert!(!$name.is_null());
-                    *$name
+                    match CStr::from_ptr($name).to_str() {
+                        Ok(s) => s,
+                        Err(e) => {
+                            swig_set_error(e);
+                            return SwigErrorValue::swig_error_value();
+                        },
+                    }
                 };
             );
-            (@ref $name:ident) => (
+            (@prim $name:ident) => {};
+            (@opt $name:ident) => (
+                let $name = unsafe { $name.as_ref().cloned() };
+            );
+            (@opt_str $name:ident) => (
                 let $name = unsafe {
-                    assert!(!$name.is_null());
-                    &*$name
+                    if $name.is_null() {
+                        None
+                    } else {
+                        match CStr::from_ptr($name).to_str() {
+                            Ok(s) => Some(s),
+                            Err(e) => {
+                                swig_set_error(e);
+                                return SwigErrorValue::swig_error_value();
+                            },
+                        }
+                    }
                 };
             );
-            (@str $name:ident) => (
-                let $name = unsafe {
-                    assert!(!$name.is_null());
-                    CStr::from_ptr($name).to_str().unwrap()
+            (@opt_string $name:ident) => (
+                ffi_ref!(@opt_str $name);
+                let $name = $name.map(|s| s.to_string());
+            );
+            (@bytes $name:ident, $len:ident) => (
+                let $name: &[u8] = unsafe {
+                    if $len == 0 {
+                        &[]
+                    } else {
+                        assert!(!$name.is_null());
+                        ::std::slice::from_raw_parts($name, $len)
+                    }
                 };
             );
-            (@prim $name:ident) => {};
+            (@bytes_mut $name:ident, $len:ident) => (
+                let $name: &mut [u8] = unsafe {
+                    if $len == 0 {
+                        &mut []
+                    } else {
+                        assert!(!$name.is_null());
+                        ::std::slice::from_raw_parts_mut($name, $len)
+                    }
+                };
+            );
+            (@bytes_owned $name:ident, $len:ident) => (
+                ffi_ref!(@bytes $name, $len);
+                let $name = ::std::convert::From::from($name);
+            );
+            (@fn $name:ident ($($arg:ident: $ty:ty),*) -> $ret:ty) => (
+                let $name = {
+                    let call = $name.call;
+                    let guard = SwigCallbackGuard {
+                        user_data: $name.user_data,
+                        drop: $name.drop,
+                    };
+                    move |$($arg: $ty),*| -> $ret { call(guard.user_data(), $($arg),*) }
+                };
+            );
+            (@box_fn $name:ident $($sig:tt)*) => (
+                ffi_ref!(@fn $name $($sig)*);
+                let $name = Box::new($name);
+            );
+            (@ref_fn $name:ident $($sig:tt)*) => (
+                ffi_ref!(@fn $name $($sig)*);
+                let $name = &$name;
+            );
+            (@mut_fn $name:ident $($sig:tt)*) => (
+                ffi_ref!(@fn $name $($sig)*);
+                let mut $name = $name;
+                let $name = &mut $name;
+            );
+            (@dyn_box $name:ident, $adapter:ident) => (
+                assert!(!$name.user_data.is_null());
+                let $name = Box::new($adapter($name));
+            );
+            // Borrowed trait objects are never dropped, so the target
+            // language keeps ownership.
+            (@dyn_ref $name:ident, $adapter:ident) => (
+                assert!(!$name.user_data.is_null());
+                let $name = ::std::mem::ManuallyDrop::new($adapter($name));
+                let $name = &*$name;
+            );
+            (@dyn_mut $name:ident, $adapter:ident) => (
+                assert!(!$name.user_data.is_null());
+                let mut $name = ::std::mem::ManuallyDrop::new($adapter($name));
+                let $name = &mut *$name;
+            );
         }
         #[allow(unused_macros)]
         macro_rules! box_ptr {
-            ($x:expr) => (
-                Box::into_raw(Box::new($x))
-            );
+            #box_arms
             (@prim $x:expr) => (
                 $x
             );
             (@str $x:expr) => (
-                CString::new($x).unwrap().into_raw()
+                match CString::new($x) {
+                    Ok(s) => s.into_raw(),
+                    Err(e) => {
+                        swig_set_error(e);
+                        ::std::ptr::null_mut()
+                    },
+                }
+            );
+            // Views borrow from `$parent` for as long as they are alive.
+            (@borrowed $x:expr, $parent:ident) => ({
+                let object: *const _ = $x;
+                let view = box_ptr!(@view object, $parent);
+                ffi_borrow!(@view view, object, $parent, false);
+                view
+            });
+            (@borrowed_mut $x:expr, $parent:ident) => ({
+                let object: *const _ = $x;
+                let view = box_ptr!(@view object, $parent);
+                ffi_borrow!(@view view, object, $parent, true);
+                view
+            });
+            (@bytes $x:expr, $len:ident) => ({
+                let b: Box<[u8]> = ::std::convert::From::from($x);
+                unsafe {
+                    assert!(!$len.is_null());
+                    *$len = b.len();
+                }
+                Box::into_raw(b) as *mut u8
+            });
+            (@bytes_like $x:expr, $len:ident) => (
+                box_ptr!(@bytes AsRef::<[u8]>::as_ref(&$x), $len)
             );
+            (@borrowed_bytes $x:expr, $len:ident) => ({
+                let b: &[u8] = $x;
+                unsafe {
+                    assert!(!$len.is_null());
+                    *$len = b.len();
+                }
+                b.as_ptr()
+            });
+            (@borrowed_str $x:expr, $len:ident) => ({
+                let s: &str = $x;
+                unsafe {
+                    assert!(!$len.is_null());
+                    *$len = s.len();
+                }
+                s.as_ptr() as *const c_char
+            });
 
         }
     };
 
     tokens.into()
 }
+
+/// Runs the futures of `async fn`s on the calling thread with
+/// `swig_block_on`, and holds the results of awaitable functions.
+fn async_runtime() -> proc_macro2::TokenStream {
+    quote! {
+        /// Run `future` to completion on this thread, for the externs of
+        /// `async fn`s which aren't awaitable.
+        #[allow(dead_code)]
+        fn swig_block_on<F: ::std::future::Future>(future: F) -> F::Output {
+            struct SwigUnpark(::std::thread::Thread);
+
+            impl ::std::task::Wake for SwigUnpark {
+                fn wake(self: ::std::sync::Arc<Self>) {
+                    self.0.unpark();
+                }
+            }
+
+            let waker = ::std::task::Waker::from(::std::sync::Arc::new(SwigUnpark(::std::thread::current())));
+            let mut cx = ::std::task::Context::from_waker(&waker);
+            let mut future = Box::pin(future);
+            loop {
+                match ::std::future::Future::poll(future.as_mut(), &mut cx) {
+                    ::std::task::Poll::Ready(res) => return res,
+                    ::std::task::Poll::Pending => ::std::thread::park(),
+                }
+            }
+        }
+
+        /// The result of an awaitable function, once its future completes on
+        /// the executor thread, or the message it panicked with.
+        #[allow(dead_code)]
+        struct SwigTask<T> {
+            state: ::std::sync::Mutex<SwigTaskState<T>>,
+        }
+
+        struct SwigTaskState<T> {
+            done: bool,
+            result: Option<Result<T, String>>,
+            notify: Option<Box<dyn Fn() + Send>>,
+        }
+
+        #[allow(dead_code)]
+        impl<T> SwigTask<T> {
+            fn lock(&self) -> ::std::sync::MutexGuard<SwigTaskState<T>> {
+                self.state.lock().unwrap_or_else(|e| e.into_inner())
+            }
+
+            fn finish(&self, result: Result<T, String>) {
+                let notify = {
+                    let mut state = self.lock();
+                    state.done = true;
+                    state.result = Some(result);
+                    state.notify.take()
+                };
+                if let Some(notify) = notify {
+                    notify();
+                }
+            }
+
+            /// Call `notify` once the result is ready, which may be straight away.
+            fn notify(&self, notify: Box<dyn Fn() + Send>) {
+                {
+                    let mut state = self.lock();
+                    if !state.done {
+                        state.notify = Some(notify);
+                        return;
+                    }
+                }
+                notify();
+            }
+
+            /// Block this thread until the result is ready.
+            fn wait(&self) {
+                let (done, ready) = ::std::sync::mpsc::channel();
+                self.notify(Box::new(move || {
+                    let _ = done.send(());
+                }));
+                let _ = ready.recv();
+            }
+
+            /// Take the result, recording an error if there is none.
+            fn take(&self) -> Option<T> {
+                match self.lock().result.take() {
+                    Some(Ok(res)) => Some(res),
+                    Some(Err(msg)) => {
+                        swig_set_error(msg);
+                        None
+                    },
+                    None => {
+                        swig_set_error("the result isn't ready, or was already taken");
+                        None
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// Without `swiggen_prelude!(async)` there is no executor, so awaitable
+/// functions fail to compile.
+fn no_executor() -> proc_macro2::TokenStream {
+    quote! {
+        #[allow(unused_macros)]
+        macro_rules! ffi_spawn {
+            ($future:expr) => (
+                compile_error!("`#[swig(awaitable)]` needs `swiggen_prelude!(async)`, which runs its future on a background thread")
+            );
+        }
+    }
+}
+
+/// The executor thread for the futures of awaitable functions, started on
+/// first use.
+fn async_executor() -> proc_macro2::TokenStream {
+    quote! {
+        /// Drives the future of an awaitable function, finishing its task
+        /// with the result.
+        #[allow(dead_code)]
+        struct SwigSpawned<F: ::std::future::Future> {
+            future: ::std::pin::Pin<Box<F>>,
+            task: ::std::sync::Arc<SwigTask<F::Output>>,
+        }
+
+        impl<F: ::std::future::Future> ::std::future::Future for SwigSpawned<F> {
+            type Output = ();
+
+            fn poll(mut self: ::std::pin::Pin<&mut Self>, cx: &mut ::std::task::Context) -> ::std::task::Poll<()> {
+                let polled = {
+                    let future = &mut self.future;
+                    ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+                        ::std::future::Future::poll(future.as_mut(), cx)
+                    }))
+                };
+                let result = match polled {
+                    Ok(::std::task::Poll::Pending) => return ::std::task::Poll::Pending,
+                    Ok(::std::task::Poll::Ready(res)) => Ok(res),
+                    Err(panic) => Err(panic.downcast_ref::<&str>().map(|s| s.to_string())
+                        .or_else(|| panic.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "the future panicked".to_string())),
+                };
+                self.task.finish(result);
+                ::std::task::Poll::Ready(())
+            }
+        }
+
+        // `dyn ::std::future::Future` isn't a trait object in Rust 2015.
+        use std::future::Future as SwigFuture;
+
+        /// A future on the executor thread, which is queued to be polled
+        /// again whenever it is woken.
+        #[allow(dead_code)]
+        struct SwigRunnable {
+            future: ::std::sync::Mutex<Option<::std::pin::Pin<Box<dyn SwigFuture<Output = ()> + Send>>>>,
+        }
+
+        impl ::std::task::Wake for SwigRunnable {
+            fn wake(self: ::std::sync::Arc<Self>) {
+                swig_schedule(self);
+            }
+        }
+
+        swig_global!(swig_executor: Option<::std::sync::mpsc::Sender<::std::sync::Arc<SwigRunnable>>>);
+
+        /// Queue `runnable` to be polled on the executor thread, which is
+        /// started on first use.
+        #[allow(dead_code)]
+        fn swig_schedule(runnable: ::std::sync::Arc<SwigRunnable>) {
+            let mut executor = swig_executor();
+            let sender = executor.get_or_insert_with(|| {
+                let (sender, receiver) = ::std::sync::mpsc::channel::<::std::sync::Arc<SwigRunnable>>();
+                ::std::thread::Builder::new().name("swig-executor".to_string()).spawn(move || {
+                    for runnable in receiver {
+                        let waker = ::std::task::Waker::from(runnable.clone());
+                        let mut future = runnable.future.lock().unwrap_or_else(|e| e.into_inner());
+                        let ready = match *future {
+                            Some(ref mut f) => {
+                                let mut cx = ::std::task::Context::from_waker(&waker);
+                                ::std::future::Future::poll(f.as_mut(), &mut cx).is_ready()
+                            },
+                            None => false,
+                        };
+                        if ready {
+                            *future = None;
+                        }
+                    }
+                }).expect("Unable to start the executor thread");
+                sender
+            });
+            // Sending only fails once the executor thread is gone.
+            let _ = sender.send(runnable);
+        }
+
+        /// Run `future` on the executor thread, returning the task which it
+        /// finishes with its result.
+        #[allow(dead_code)]
+        fn swig_spawn<F>(future: F) -> ::std::sync::Arc<SwigTask<F::Output>>
+            where F: ::std::future::Future + Send + 'static, F::Output: Send + 'static
+        {
+            let task = ::std::sync::Arc::new(SwigTask {
+                state: ::std::sync::Mutex::new(SwigTaskState { done: false, result: None, notify: None }),
+            });
+            let spawned = SwigSpawned { future: Box::pin(future), task: task.clone() };
+            swig_schedule(::std::sync::Arc::new(SwigRunnable {
+                future: ::std::sync::Mutex::new(Some(Box::pin(spawned))),
+            }));
+            task
+        }
+
+        #[allow(unused_macros)]
+        macro_rules! ffi_spawn {
+            ($future:expr) => (swig_spawn($future));
+        }
+    }
+}
+
+/// Without borrow checking, the borrows of objects are not tracked.
+fn unchecked_borrows() -> proc_macro2::TokenStream {
+    quote! {
+        #[allow(unused_macros)]
+        macro_rules! ffi_borrow {
+            (@shared $name:ident) => ();
+            (@exclusive $name:ident) => ();
+            (@view $view:ident, $object:ident, $parent:ident, $exclusive:expr) => ();
+            (@end_view $view:ident) => ();
+            (@held $name:ident) => (());
+            (@release_gil) => (
+                compile_error!("`#[swig(release_gil)]` on a function taking objects needs \
+                                `swiggen_prelude!(borrow_checking)`, as other threads can use them meanwhile");
+            );
+        }
+    }
+}
+
+/// Tracks the shared and exclusive borrows of each object, by its type and
+/// address. Objects are borrowed by each extern taking them for the length
+/// of the call, and by views returned from them for as long as the view is
+/// alive. A borrow which conflicts with another one is raised as a
+/// `BorrowError` instead, without calling into Rust.
+fn checked_borrows() -> proc_macro2::TokenStream {
+    quote! {
+        #[derive(Default)]
+        struct SwigBorrowState {
+            shared: usize,
+            exclusive: usize,
+        }
+
+        type SwigBorrowKey = (::std::any::TypeId, usize);
+
+        /// A view's borrow of its parent, and the object it points to.
+        struct SwigView {
+            parent: SwigBorrowKey,
+            exclusive: bool,
+            object: SwigBorrowKey,
+        }
+
+        /// The borrows of each object, and the object each view borrows from.
+        #[derive(Default)]
+        struct SwigBorrows {
+            objects: ::std::collections::HashMap<SwigBorrowKey, SwigBorrowState>,
+            views: ::std::collections::HashMap<SwigBorrowKey, Vec<SwigView>>,
+        }
+
+        impl SwigBorrows {
+            fn add(&mut self, key: SwigBorrowKey, exclusive: bool) {
+                let state = self.objects.entry(key).or_insert_with(SwigBorrowState::default);
+                if exclusive {
+                    state.exclusive += 1;
+                } else {
+                    state.shared += 1;
+                }
+            }
+
+            fn release(&mut self, key: SwigBorrowKey, exclusive: bool) {
+                let done = match self.objects.get_mut(&key) {
+                    Some(state) => {
+                        if exclusive {
+                            state.exclusive -= 1;
+                        } else {
+                            state.shared -= 1;
+                        }
+                        state.shared == 0 && state.exclusive == 0
+                    },
+                    None => false,
+                };
+                if done {
+                    self.objects.remove(&key);
+                }
+            }
+        }
+
+        swig_global!(swig_borrows: SwigBorrows);
+
+        fn swig_borrow_key<T: 'static>(ptr: *const T) -> SwigBorrowKey {
+            (::std::any::TypeId::of::<T>(), ptr as usize)
+        }
+
+        /// Released once the extern borrowing the object returns.
+        struct SwigBorrowGuard {
+            key: SwigBorrowKey,
+            exclusive: bool,
+        }
+
+        impl Drop for SwigBorrowGuard {
+            fn drop(&mut self) {
+                swig_borrows().release(self.key, self.exclusive);
+            }
+        }
+
+        #[allow(dead_code)]
+        fn swig_borrow<T: 'static>(obj: *const T, exclusive: bool) -> Option<SwigBorrowGuard> {
+            let key = swig_borrow_key(obj);
+            let mut borrows = swig_borrows();
+            let conflict = match borrows.objects.get(&key) {
+                Some(state) if state.exclusive > 0 => Some("already mutably borrowed"),
+                Some(state) if exclusive && state.shared > 0 => Some("already borrowed"),
+                _ => None,
+            };
+            match conflict {
+                Some(msg) => {
+                    swig_set_error_kind(SWIG_ERROR_BORROW, msg);
+                    None
+                },
+                None => {
+                    borrows.add(key, exclusive);
+                    Some(SwigBorrowGuard { key, exclusive })
+                },
+            }
+        }
+
+        /// Borrow `parent` for as long as `view` of `object` is alive. The
+        /// borrow is taken from the one held by the extern returning the
+        /// view, so doesn't conflict with it. A shared view is also a shared
+        /// borrow of `object`, so it can't be mutated through the view.
+        #[allow(dead_code)]
+        fn swig_borrow_view<T: 'static, P: 'static>(view: *const T, object: *const T, parent: &P, exclusive: bool) {
+            if view.is_null() {
+                return;
+            }
+            let (parent, object) = (swig_borrow_key(parent), swig_borrow_key(object));
+            let mut borrows = swig_borrows();
+            borrows.add(parent, exclusive);
+            if !exclusive {
+                borrows.add(object, false);
+            }
+            borrows.views.entry(swig_borrow_key(view)).or_insert_with(Vec::new).push(SwigView { parent, exclusive, object });
+        }
+
+        #[allow(dead_code)]
+        fn swig_borrow_end_view<T: 'static>(view: *const T) {
+            let mut borrows = swig_borrows();
+            let key = swig_borrow_key(view);
+            let view = borrows.views.get_mut(&key).and_then(|views| views.pop());
+            if borrows.views.get(&key).map_or(false, |views| views.is_empty()) {
+                borrows.views.remove(&key);
+            }
+            if let Some(view) = view {
+                borrows.release(view.parent, view.exclusive);
+                if !view.exclusive {
+                    borrows.release(view.object, false);
+                }
+            }
+        }
+
+        #[allow(unused_macros)]
+        macro_rules! ffi_borrow {
+            (@shared $name:ident) => (
+                let _guard = match swig_borrow($name, false) {
+                    Some(guard) => guard,
+                    None => return SwigErrorValue::swig_error_value(),
+                };
+            );
+            (@exclusive $name:ident) => (
+                let _guard = match swig_borrow($name, true) {
+                    Some(guard) => guard,
+                    None => return SwigErrorValue::swig_error_value(),
+                };
+            );
+            (@view $view:ident, $object:ident, $parent:ident, $exclusive:expr) => (
+                swig_borrow_view($view, $object, $parent, $exclusive)
+            );
+            (@end_view $view:ident) => (
+                swig_borrow_end_view($view)
+            );
+            (@release_gil) => ();
+            // Another shared borrow, held by the future of an awaitable
+            // method until it completes.
+            (@held $name:ident) => (
+                match swig_borrow($name, false) {
+                    Some(guard) => guard,
+                    None => return SwigErrorValue::swig_error_value(),
+                }
+            );
+        }
+    }
+}
+
+/// The parts of the prelude which depend on how objects are passed across:
+/// the supporting runtime, and the `ffi_ref!` and `box_ptr!` arms taking and
+/// handing out objects, including `@view`s borrowed from another object.
+/// Both also define `ffi_free!`, to release an object handed out as a box
+/// (`@object` for exported types, `@box` for iterators), an `@arc`, `@rc` or
+/// a `@borrowed` view, and `ffi_share!` to hand out another `@arc` or `@rc`.
+struct Handles {
+    runtime: proc_macro2::TokenStream,
+    ref_arms: proc_macro2::TokenStream,
+    box_arms: proc_macro2::TokenStream,
+}
+
+/// Objects are passed across as raw pointers, which are only checked for null.
+fn raw_handles() -> Handles {
+    let runtime = quote! {
+        #[allow(unused_macros)]
+        macro_rules! ffi_free {
+            (@object $name:ident) => (
+                ffi_thread!(@check $name);
+                ffi_thread!(@released $name);
+                unsafe {
+                    assert!(!$name.is_null());
+                    drop(Box::from_raw($name));
+                }
+            );
+            (@box $name:ident) => (
+                ffi_thread!(@check $name);
+                ffi_thread!(@released $name);
+                unsafe {
+                    assert!(!$name.is_null());
+                    drop(Box::from_raw($name));
+                }
+            );
+            (@arc $name:ident) => (
+                ffi_thread!(@check $name);
+                ffi_thread!(@released $name);
+                unsafe {
+                    assert!(!$name.is_null());
+                    drop(::std::sync::Arc::from_raw($name));
+                }
+            );
+            (@rc $name:ident) => (
+                ffi_thread!(@check $name);
+                ffi_thread!(@released $name);
+                unsafe {
+                    assert!(!$name.is_null());
+                    drop(::std::rc::Rc::from_raw($name));
+                }
+            );
+            (@borrowed $name:ident) => (
+                ffi_thread!(@check $name);
+                ffi_thread!(@released $name);
+            );
+        }
+
+        #[allow(unused_macros)]
+        macro_rules! ffi_share {
+            (@arc $name:ident) => ({
+                ffi_thread!(@check $name);
+                let other = unsafe {
+                    assert!(!$name.is_null());
+                    let arc = ::std::sync::Arc::from_raw($name);
+                    let other = ::std::sync::Arc::clone(&arc);
+                    ::std::mem::forget(arc);
+                    ::std::sync::Arc::into_raw(other)
+                };
+                ffi_thread!(@created other);
+                other as *mut _
+            });
+            (@rc $name:ident) => ({
+                ffi_thread!(@check $name);
+                let other = unsafe {
+                    assert!(!$name.is_null());
+                    let rc = ::std::rc::Rc::from_raw($name);
+                    let other = ::std::rc::Rc::clone(&rc);
+                    ::std::mem::forget(rc);
+                    ::std::rc::Rc::into_raw(other)
+                };
+                ffi_thread!(@created other);
+                other as *mut _
+            });
+        }
+    };
+    let ref_arms = quote! {
+        ($name:ident) => (
+            let $name = unsafe {
+                assert!(!$name.is_null());
+                *$name
+            };
+        );
+        (@ref $name:ident) => (
+            let $name = unsafe {
+                assert!(!$name.is_null());
+                &*$name
+            };
+            ffi_thread!(@check $name);
+            ffi_borrow!(@shared $name);
+        );
+        // The borrow is checked before creating the `&mut`, so it never
+        // aliases another reference.
+        (@mut $name:ident) => (
+            assert!(!$name.is_null());
+            ffi_thread!(@check $name);
+            ffi_borrow!(@exclusive $name);
+            let $name = unsafe { &mut *$name };
+        );
+    };
+    let box_arms = quote! {
+        ($x:expr) => ({
+            let ptr = Box::into_raw(Box::new($x));
+            ffi_thread!(@created ptr);
+            ptr
+        });
+        (@arc $x:expr) => ({
+            let ptr = ::std::sync::Arc::into_raw($x);
+            ffi_thread!(@created ptr);
+            ptr as *mut _
+        });
+        (@rc $x:expr) => ({
+            let ptr = ::std::rc::Rc::into_raw($x);
+            ffi_thread!(@created ptr);
+            ptr as *mut _
+        });
+        (@borrowed $x:expr) => ({
+            let ptr: *const _ = $x;
+            ffi_thread!(@created ptr);
+            ptr as *mut _
+        });
+        (@view $x:expr, $parent:ident) => (box_ptr!(@borrowed $x));
+    };
+    Handles { runtime, ref_arms, box_arms }
+}
+
+/// Objects are passed across as 64-bit handles into a table of the live
+/// objects of each type. The lower 32 bits of a handle index a slot in the
+/// table, and the upper 32 bits are the generation of the slot, which is
+/// bumped whenever its object is freed. So stale, forged and double freed
+/// handles are caught, and raised as errors in the target language.
+/// Objects are pinned while a call uses them, so freeing one from another
+/// thread only drops it once the call returns, and views are only valid for
+/// as long as the object they borrow from.
+/// Handles still have pointer types in the externs, but are never
+/// dereferenced on the other side.
+fn safe_handles() -> Handles {
+    let runtime = quote! {
+        #[cfg(not(target_pointer_width = "64"))]
+        compile_error!("`swiggen_prelude!(safe_handles)` needs 64-bit pointers to pass handles in");
+
+        /// How the object behind a handle is owned.
+        #[allow(dead_code)]
+        #[derive(Clone, Copy, PartialEq)]
+        enum SwigOwner { Box, Arc, Rc, Borrowed }
+
+        /// A slot in the table of a type, with the generation of the object
+        /// in it.
+        type SwigSlotRef = (::std::any::TypeId, u32, u32);
+
+        struct SwigEntry {
+            ptr: usize,
+            owner: SwigOwner,
+            drop: unsafe fn(usize),
+            /// The number of calls using the object, which keep it from
+            /// being dropped when its handle is freed.
+            pins: usize,
+            freed: bool,
+            /// The object a view is borrowed from.
+            parent: Option<SwigSlotRef>,
+        }
+
+        struct SwigSlot {
+            generation: u32,
+            entry: Option<SwigEntry>,
+        }
+
+        /// The live objects of a single type, and the free slots to reuse.
+        /// The slots pinned by calls are also kept by address (once per
+        /// pin), to find the parent of a view without a scan.
+        #[derive(Default)]
+        struct SwigTable {
+            slots: Vec<SwigSlot>,
+            free: Vec<u32>,
+            pinned: ::std::collections::HashMap<usize, Vec<u32>>,
+        }
+
+        impl SwigTable {
+            /// The slot of `handle`, if its object is live and owned as `owner`.
+            #[allow(dead_code)]
+            fn live(&mut self, handle: usize, owner: Option<SwigOwner>) -> Option<&mut SwigSlot> {
+                let (index, generation) = (handle as u32, (handle as u64 >> 32) as u32);
+                let slot = self.slots.get_mut(index as usize)?;
+                match slot.entry {
+                    Some(ref e) if slot.generation == generation && owner.map_or(true, |o| o == e.owner) => {},
+                    _ => return None,
+                }
+                Some(slot)
+            }
+        }
+
+        type SwigTables = ::std::collections::HashMap<::std::any::TypeId, SwigTable>;
+
+        swig_global!(swig_tables: SwigTables);
+
+        /// Whether the object in a slot is still live, and so are all the
+        /// objects it is borrowed from.
+        #[allow(dead_code)]
+        fn swig_alive(tables: &SwigTables, slot: SwigSlotRef) -> bool {
+            let mut next = Some(slot);
+            while let Some((type_id, index, generation)) = next {
+                let slot = match tables.get(&type_id).and_then(|table| table.slots.get(index as usize)) {
+                    Some(slot) => slot,
+                    None => return false,
+                };
+                next = match slot.entry {
+                    Some(ref e) if slot.generation == generation => e.parent,
+                    _ => return false,
+                };
+            }
+            true
+        }
+
+        unsafe fn swig_drop_box<T>(ptr: usize) {
+            drop(Box::from_raw(ptr as *mut T));
+        }
+
+        unsafe fn swig_drop_arc<T>(ptr: usize) {
+            drop(::std::sync::Arc::from_raw(ptr as *const T));
+        }
+
+        unsafe fn swig_drop_rc<T>(ptr: usize) {
+            drop(::std::rc::Rc::from_raw(ptr as *const T));
+        }
+
+        unsafe fn swig_drop_borrowed(_ptr: usize) {}
+
+        /// Hand out a new handle to `ptr`, which is owned as `owner`, and
+        /// borrowed from `parent` if it is a view.
+        #[allow(dead_code)]
+        fn swig_handle_new<T: 'static>(ptr: *const T, owner: SwigOwner, parent: Option<SwigSlotRef>) -> *mut T {
+            let drop: unsafe fn(usize) = match owner {
+                SwigOwner::Box => swig_drop_box::<T>,
+                SwigOwner::Arc => swig_drop_arc::<T>,
+                SwigOwner::Rc => swig_drop_rc::<T>,
+                SwigOwner::Borrowed => swig_drop_borrowed,
+            };
+            let entry = Some(SwigEntry { ptr: ptr as usize, owner, drop, pins: 0, freed: false, parent });
+            let mut tables = swig_tables();
+            let table = tables.entry(::std::any::TypeId::of::<T>()).or_insert_with(SwigTable::default);
+            let index = match table.free.pop() {
+                Some(index) => {
+                    table.slots[index as usize].entry = entry;
+                    index
+                },
+                None => {
+                    table.slots.push(SwigSlot { generation: 1, entry });
+                    (table.slots.len() - 1) as u32
+                },
+            };
+            let generation = table.slots[index as usize].generation;
+            ((generation as u64) << 32 | index as u64) as usize as *mut T
+        }
+
+        /// Hand out a view of `ptr`, which is only valid for as long as the
+        /// handle of `parent` is. The parent is found among the objects
+        /// pinned by the call returning the view.
+        #[allow(dead_code)]
+        fn swig_handle_view<T: 'static, P: 'static>(ptr: *const T, parent: &P) -> *mut T {
+            let type_id = ::std::any::TypeId::of::<P>();
+            let parent = {
+                let tables = swig_tables();
+                tables.get(&type_id).and_then(|table| {
+                    let pinned = table.pinned.get(&(parent as *const P as usize))?;
+                    pinned.iter().rev().find_map(|&index| {
+                        let slot = &table.slots[index as usize];
+                        match slot.entry {
+                            Some(ref e) if !e.freed => Some((type_id, index, slot.generation)),
+                            _ => None,
+                        }
+                    })
+                })
+            };
+            match parent {
+                Some(parent) => swig_handle_new(ptr, SwigOwner::Borrowed, Some(parent)),
+                None => {
+                    swig_set_error("object was freed while a view of it was taken");
+                    ::std::ptr::null_mut()
+                },
+            }
+        }
+
+        /// Keeps the object behind a handle from being dropped until the
+        /// call using it returns. If its handle was freed in the meantime,
+        /// the object is dropped then.
+        struct SwigPin {
+            type_id: ::std::any::TypeId,
+            index: u32,
+        }
+
+        impl Drop for SwigPin {
+            fn drop(&mut self) {
+                let entry = {
+                    let mut tables = swig_tables();
+                    let table = tables.get_mut(&self.type_id).expect("pinned object without a table");
+                    let (ptr, done) = match table.slots[self.index as usize].entry {
+                        Some(ref mut e) => {
+                            e.pins -= 1;
+                            (e.ptr, e.pins == 0 && e.freed)
+                        },
+                        None => unreachable!("pinned objects aren't dropped"),
+                    };
+                    let unpinned = match table.pinned.get_mut(&ptr) {
+                        Some(pinned) => {
+                            if let Some(pos) = pinned.iter().rposition(|&index| index == self.index) {
+                                pinned.swap_remove(pos);
+                            }
+                            pinned.is_empty()
+                        },
+                        None => false,
+                    };
+                    if unpinned {
+                        table.pinned.remove(&ptr);
+                    }
+                    if done {
+                        table.free.push(self.index);
+                        table.slots[self.index as usize].entry.take()
+                    } else {
+                        None
+                    }
+                };
+                // Dropped once the table is unlocked, as it may free other objects.
+                if let Some(e) = entry {
+                    unsafe { (e.drop)(e.ptr) }
+                }
+            }
+        }
+
+        /// Pin the object behind `handle` until the returned pin is dropped,
+        /// or record an error if there is no such object, or it is a view
+        /// of one which has been freed since.
+        #[allow(dead_code)]
+        fn swig_handle_pin<T: 'static>(handle: *const T) -> Option<(*mut T, SwigPin)> {
+            let type_id = ::std::any::TypeId::of::<T>();
+            let (index, generation) = (handle as usize as u32, (handle as usize as u64 >> 32) as u32);
+            let res = {
+                let mut tables = swig_tables();
+                if swig_alive(&tables, (type_id, index, generation)) {
+                    tables.get_mut(&type_id).and_then(|table| {
+                        let ptr = {
+                            let e = table.slots[index as usize].entry.as_mut()?;
+                            e.pins += 1;
+                            e.ptr
+                        };
+                        table.pinned.entry(ptr).or_insert_with(Vec::new).push(index);
+                        Some((ptr as *mut T, SwigPin { type_id, index }))
+                    })
+                } else {
+                    None
+                }
+            };
+            if res.is_none() {
+                swig_set_error("use of a freed or invalid object handle");
+            }
+            res
+        }
+
+        /// Run `f` on the address of the object behind `handle` while the
+        /// table is locked, or record an error if there is no such object.
+        #[allow(dead_code)]
+        fn swig_handle_with<T: 'static, R, F: FnOnce(usize) -> R>(handle: *const T, owner: Option<SwigOwner>, f: F) -> Option<R> {
+            let res = swig_tables().get_mut(&::std::any::TypeId::of::<T>())
+                .and_then(|table| table.live(handle as usize, owner))
+                .and_then(|slot| slot.entry.as_ref().map(|e| f(e.ptr)));
+            if res.is_none() {
+                swig_set_error("use of a freed or invalid object handle");
+            }
+            res
+        }
+
+        /// The address of the object behind `handle`, which is only used to
+        /// look up its thread, as it isn't pinned.
+        #[allow(dead_code)]
+        fn swig_handle_get<T: 'static>(handle: *const T) -> Option<*mut T> {
+            swig_handle_with(handle, None, |ptr| ptr as *mut T)
+        }
+
+        /// Hand out another handle to the object behind `handle`, taking a
+        /// new strong reference for shared objects.
+        #[allow(dead_code)]
+        fn swig_handle_share<T: 'static>(handle: *const T, owner: SwigOwner) -> *mut T {
+            let ptr = swig_handle_with(handle, Some(owner), |ptr| {
+                unsafe {
+                    match owner {
+                        SwigOwner::Arc => {
+                            let arc = ::std::sync::Arc::from_raw(ptr as *const T);
+                            ::std::mem::forget(::std::sync::Arc::clone(&arc));
+                            ::std::mem::forget(arc);
+                        },
+                        SwigOwner::Rc => {
+                            let rc = ::std::rc::Rc::from_raw(ptr as *const T);
+                            ::std::mem::forget(::std::rc::Rc::clone(&rc));
+                            ::std::mem::forget(rc);
+                        },
+                        _ => {},
+                    }
+                }
+                ptr
+            });
+            match ptr {
+                Some(ptr) => swig_handle_new(ptr as *const T, owner, None),
+                None => ::std::ptr::null_mut(),
+            }
+        }
+
+        /// Release the object behind `handle`, invalidating the handle, and
+        /// any views of it. Objects still pinned by a call are dropped once
+        /// it returns.
+        #[allow(dead_code)]
+        fn swig_handle_free<T: 'static>(handle: *const T, owner: SwigOwner) {
+            let entry = {
+                let mut tables = swig_tables();
+                match tables.get_mut(&::std::any::TypeId::of::<T>()) {
+                    Some(table) => {
+                        let index = handle as usize as u32;
+                        let pinned = table.live(handle as usize, Some(owner)).map(|slot| {
+                            slot.generation = slot.generation.wrapping_add(1).max(1);
+                            let e = slot.entry.as_mut().unwrap();
+                            e.freed = true;
+                            e.pins > 0
+                        });
+                        match pinned {
+                            Some(false) => {
+                                table.free.push(index);
+                                Ok(table.slots[index as usize].entry.take())
+                            },
+                            Some(true) => Ok(None),
+                            None => Err(()),
+                        }
+                    },
+                    None => Err(()),
+                }
+            };
+            // Dropped once the table is unlocked, as it may free other objects.
+            match entry {
+                Ok(Some(e)) => unsafe { (e.drop)(e.ptr) },
+                Ok(None) => {},
+                Err(()) => swig_set_error("double free of an object handle, or an invalid handle"),
+            }
+        }
+
+        // The thread of an object is checked before its handle is freed or
+        // shared, as the handle table doesn't know about it.
+        #[allow(unused_macros)]
+        macro_rules! ffi_free {
+            (@object $name:ident) => (ffi_free!(@handle $name, SwigOwner::Box));
+            (@box $name:ident) => (ffi_free!(@handle $name, SwigOwner::Box));
+            (@arc $name:ident) => (ffi_free!(@handle $name, SwigOwner::Arc));
+            (@rc $name:ident) => (ffi_free!(@handle $name, SwigOwner::Rc));
+            (@borrowed $name:ident) => (ffi_free!(@handle $name, SwigOwner::Borrowed));
+            (@handle $name:ident, $owner:expr) => (
+                if let Some(ptr) = swig_handle_get($name) {
+                    ffi_thread!(@check ptr);
+                    ffi_thread!(@released ptr);
+                    swig_handle_free($name, $owner);
+                }
+            );
+        }
+
+        #[allow(unused_macros)]
+        macro_rules! ffi_share {
+            (@arc $name:ident) => (ffi_share!(@handle $name, SwigOwner::Arc));
+            (@rc $name:ident) => (ffi_share!(@handle $name, SwigOwner::Rc));
+            (@handle $name:ident, $owner:expr) => (
+                match swig_handle_get($name) {
+                    Some(ptr) => {
+                        ffi_thread!(@check ptr);
+                        ffi_thread!(@created ptr);
+                        swig_handle_share($name, $owner)
+                    },
+                    None => ::std::ptr::null_mut(),
+                }
+            );
+        }
+    };
+    let ref_arms = quote! {
+        ($name:ident) => (
+            ffi_ref!(@ref $name);
+            let $name = *$name;
+        );
+        (@ref $name:ident) => (
+            let ($name, _pin) = match swig_handle_pin($name) {
+                Some((ptr, pin)) => (unsafe { &*ptr }, pin),
+                None => return SwigErrorValue::swig_error_value(),
+            };
+            ffi_thread!(@check $name);
+            ffi_borrow!(@shared $name);
+        );
+        (@mut $name:ident) => (
+            let ($name, _pin) = match swig_handle_pin($name) {
+                Some(pinned) => pinned,
+                None => return SwigErrorValue::swig_error_value(),
+            };
+            ffi_thread!(@check $name);
+            ffi_borrow!(@exclusive $name);
+            let $name = unsafe { &mut *$name };
+        );
+    };
+    let box_arms = quote! {
+        ($x:expr) => ({
+            let ptr = Box::into_raw(Box::new($x));
+            ffi_thread!(@created ptr);
+            swig_handle_new(ptr, SwigOwner::Box, None)
+        });
+        (@arc $x:expr) => ({
+            let ptr = ::std::sync::Arc::into_raw($x);
+            ffi_thread!(@created ptr);
+            swig_handle_new(ptr, SwigOwner::Arc, None)
+        });
+        (@rc $x:expr) => ({
+            let ptr = ::std::rc::Rc::into_raw($x);
+            ffi_thread!(@created ptr);
+            swig_handle_new(ptr, SwigOwner::Rc, None)
+        });
+        (@borrowed $x:expr) => ({
+            let ptr: *const _ = $x;
+            ffi_thread!(@created ptr);
+            swig_handle_new(ptr, SwigOwner::Borrowed, None)
+        });
+        (@view $x:expr, $parent:ident) => ({
+            let ptr: *const _ = $x;
+            ffi_thread!(@created ptr);
+            swig_handle_view(ptr, $parent)
+        });
+    };
+    Handles { runtime, ref_arms, box_arms }
+}
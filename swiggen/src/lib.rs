@@ -1,21 +1,18 @@
-#![allow(unused_doc_comment)]
-
-/// # swiggen
-/// 
-/// The `swiggen` library is used to generate `extern "C"` definitions and
-/// SWIG wrapper code from Rust functions.
-///
-/// This basically does two things: generates the `extern "C"` methods by
-/// applying typemaps from cbindgen, or some fairly crude heuristics - 
-/// such as converting an opaque `Foo` into a `*mut Foo`, and running
-/// `Box::into_raw(Box::new(foo))` to convert it into a pointer.
-///
-/// These exported functions all have mangled names like `__SWIG_INJECT_new_Foo`.
-/// The code also generates SWIG wrapper code which wraps these functions sp
-/// that `Foo` behaves like a native object with methods like `Foo.new`.
-/// The SWIG code is injected into the expanded Rust source code through doc
-/// comments on various structs/functions.
-
+//! # swiggen
+//! 
+//! The `swiggen` library is used to generate `extern "C"` definitions and
+//! SWIG wrapper code from Rust functions.
+//!
+//! This basically does two things: generates the `extern "C"` methods by
+//! applying typemaps from cbindgen, or some fairly crude heuristics - 
+//! such as converting an opaque `Foo` into a `*mut Foo`, and running
+//! `Box::into_raw(Box::new(foo))` to convert it into a pointer.
+//!
+//! These exported functions all have mangled names like `__SWIG_INJECT_new_Foo`.
+//! The code also generates SWIG wrapper code which wraps these functions sp
+//! that `Foo` behaves like a native object with methods like `Foo.new`.
+//! The SWIG code is injected into the expanded Rust source code through doc
+//! comments on various structs/functions.
 
 extern crate cbindgen;
 #[macro_use]
@@ -26,7 +23,7 @@ extern crate quote;
 #[macro_use]
 extern crate syn;
 
-use proc_macro2::{Span, TokenStream};
+use proc_macro2::{Group, Span, TokenStream, TokenTree};
 use quote::ToTokens;
 use quote::TokenStreamExt;
 
@@ -46,6 +43,10 @@ enum SwigTag {
     CodeEnd,
     HdrStart,
     HdrEnd,
+    PyiStart,
+    PyiEnd,
+    PyModule,
+    PyClass,
     SwigInject,
 }
 
@@ -63,6 +64,10 @@ impl SwigTag {
             SwigTag::CodeEnd   => "__SWIG_END_CODE\n",
             SwigTag::HdrStart  => "__SWIG_HDR\n",
             SwigTag::HdrEnd    => "__SWIG_END_HDR\n",
+            SwigTag::PyiStart  => "__SWIG_PYI\n",
+            SwigTag::PyiEnd    => "__SWIG_END_PYI\n",
+            SwigTag::PyModule  => "__SWIG_PY_MODULE",
+            SwigTag::PyClass   => "__SWIG_PY_CLASS",
             SwigTag::SwigInject=> "__SWIG_INJECT_",
         }
     }
@@ -74,6 +79,10 @@ impl SwigTag {
             SwigTag::CodeEnd   => "__SWIG_END_CODE\n",
             SwigTag::HdrStart  => "__SWIG_HDR\n",
             SwigTag::HdrEnd    => "__SWIG_END_HDR\n",
+            SwigTag::PyiStart  => "__SWIG_PYI\n",
+            SwigTag::PyiEnd    => "__SWIG_END_PYI\n",
+            SwigTag::PyModule  => "__SWIG_PY_MODULE",
+            SwigTag::PyClass   => "__SWIG_PY_CLASS",
             SwigTag::SwigInject=> "__SWIG_INJECT_",
         }.len()
     }
@@ -91,51 +100,464 @@ pub trait AsExtern {
 
 impl AsExtern for syn::DeriveInput {
     fn as_extern(&self) -> TokenStream {
-        let name = &self.ident;
-        let free_name = swig_free(&name);
-        // For an stuct we want to derive Swig for, we add a `free_Foo`
-        // method so we can free it from SWIG code.
-        let mut tokens = quote! {
-            #[allow(non_snake_case)]
-            #[no_mangle]
-            pub extern "C" fn #free_name(arg: *mut #name) {
-                unsafe {
-                    assert!(!arg.is_null());
-                    &*arg;
-                }
-            }
-        };
-        let default_name = swig_fn(&name, "default");
-
-        // TOOD: Add more derive capabilities
-        // Extracting the derived methods from `#[swig_derive(...)]`.
-        // We need to automatically add the SWIG code since we cant somehow
-        // add the `#[swiggen(Foo)]` attribute to the derived methods.
-        let derivs = get_derives(&self.attrs);
-        let new_toks = derivs.iter().filter_map(|w| {
-            match w.as_str() {
-                "Default" => {
-                    Some(quote! {
-                        #[allow(non_snake_case)]
-                        #[no_mangle]
-                        pub extern "C" fn #default_name() -> *mut #name {
-                            Box::into_raw(Box::new(#name::default()))
-                        }
-                    })
-                },
-                _ => None
-            }
-        });
-        tokens.append_all(new_toks);
+        let mut tokens = TokenStream::new();
+        for (ty, class) in derive_instances(self) {
+            tokens.append_all(derive_extern(self, &ty, &class));
+        }
         tokens
     }
 }
 
+/// The concrete types a `#[derive(Swig)]` item is exported as, with the
+/// name of each wrapper class. Generic types need a
+/// `#[swig_instantiate(..)]` attribute listing the instances to export.
+fn derive_instances(ast: &syn::DeriveInput) -> Vec<(syn::Type, String)> {
+    let name = &ast.ident;
+    let params: Vec<&syn::Ident> = ast.generics.type_params().map(|tp| &tp.ident).collect();
+    if params.is_empty() {
+        return vec![(parse_quote!(#name), name.to_string())];
+    }
+    let instances = get_instances(&ast.attrs);
+    if instances.is_empty() {
+        panic!("Cannot export generic type `{}` without concrete types.
+                Try: `#[swig_instantiate(T = u32)]` on the type", name);
+    }
+    instances.iter().map(|inst| {
+        let args: Vec<syn::Type> = params.iter().map(|p| {
+            inst.get(p).cloned()
+                .unwrap_or_else(|| panic!("No type given for generic parameter `{}`", p))
+        }).collect();
+        let class = name.to_string() + &class_suffix(&args);
+        (parse_quote!(#name<#(#args),*>), class)
+    }).collect()
+}
+
+fn derive_extern(ast: &syn::DeriveInput, ty: &syn::Type, class: &str) -> TokenStream {
+    let class = syn::Ident::new(class, Span::call_site());
+    let free_name = swig_free(&class);
+    // For an stuct we want to derive Swig for, we add a `free_Foo`
+    // method so we can free it from SWIG code.
+    let mut tokens = quote! {
+        #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+        #[no_mangle]
+        pub extern "C" fn #free_name(arg: *mut #ty) {
+            ffi_free!(@object arg);
+        }
+    };
+    // Objects handed out as an `Arc` or `Rc` are released by dropping the
+    // strong reference, and copied by taking another one. Views borrowed
+    // from another object only release their handle.
+    let free_arc = swig_fn(&class, "free_arc");
+    let free_rc = swig_fn(&class, "free_rc");
+    let free_borrowed = swig_fn(&class, "free_borrowed");
+    let share_arc = swig_fn(&class, "share_arc");
+    let share_rc = swig_fn(&class, "share_rc");
+    tokens.append_all(quote! {
+        #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+        #[no_mangle]
+        pub extern "C" fn #free_arc(arg: *const #ty) {
+            ffi_free!(@arc arg);
+        }
+
+        #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+        #[no_mangle]
+        pub extern "C" fn #free_rc(arg: *const #ty) {
+            ffi_free!(@rc arg);
+        }
+
+        #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+        #[no_mangle]
+        pub extern "C" fn #free_borrowed(arg: *const #ty) {
+            ffi_borrow!(@end_view arg);
+            ffi_free!(@borrowed arg);
+        }
+
+        #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+        #[no_mangle]
+        pub extern "C" fn #share_arc(arg: *const #ty) -> *mut #ty {
+            ffi_share!(@arc arg)
+        }
+
+        #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+        #[no_mangle]
+        pub extern "C" fn #share_rc(arg: *const #ty) -> *mut #ty {
+            ffi_share!(@rc arg)
+        }
+    });
+    // Types which aren't `Send` and `Sync` are only used from the thread
+    // which created each object, which can also be asked for explicitly.
+    if SwigOptions::from_attrs(&ast.attrs).unsendable {
+        tokens.append_all(quote! {
+            impl SwigUnsendable for #ty {}
+        });
+    }
+    let default_name = swig_fn(&class, "default");
+    let clone_name = swig_fn(&class, "clone");
+    let debug_name = swig_fn(&class, "debug");
+    let display_name = swig_fn(&class, "display");
+    let eq_name = swig_fn(&class, "eq");
+    let hash_name = swig_fn(&class, "hash");
+    let cmp_name = swig_fn(&class, "cmp");
+
+    // Extracting the derived methods from `#[swig_derive(...)]`.
+    // We need to automatically add the SWIG code since we cant somehow
+    // add the `#[swiggen(Foo)]` attribute to the derived methods.
+    let derivs = get_derives(&ast.attrs);
+    let new_toks = derivs.iter().map(|w| {
+        match w.as_str() {
+            "Default" => quote! {
+                #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+                #[no_mangle]
+                pub extern "C" fn #default_name() -> *mut #ty {
+                    box_ptr!(<#ty>::default())
+                }
+            },
+            "Clone" => quote! {
+                #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+                #[no_mangle]
+                pub extern "C" fn #clone_name(arg: *const #ty) -> *mut #ty {
+                    ffi_ref!(@ref arg);
+                    box_ptr!(arg.clone())
+                }
+            },
+            "Debug" => quote! {
+                #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+                #[no_mangle]
+                pub extern "C" fn #debug_name(arg: *const #ty) -> *mut c_char {
+                    ffi_ref!(@ref arg);
+                    box_ptr!(@str format!("{:?}", arg))
+                }
+            },
+            "Display" => quote! {
+                #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+                #[no_mangle]
+                pub extern "C" fn #display_name(arg: *const #ty) -> *mut c_char {
+                    ffi_ref!(@ref arg);
+                    box_ptr!(@str format!("{}", arg))
+                }
+            },
+            "PartialEq" => quote! {
+                #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+                #[no_mangle]
+                pub extern "C" fn #eq_name(arg: *const #ty, other: *const #ty) -> bool {
+                    ffi_ref!(@ref arg);
+                    ffi_ref!(@ref other);
+                    arg == other
+                }
+            },
+            "Hash" => quote! {
+                #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+                #[no_mangle]
+                pub extern "C" fn #hash_name(arg: *const #ty) -> u64 {
+                    use std::hash::{Hash, Hasher};
+                    ffi_ref!(@ref arg);
+                    let mut hasher = ::std::collections::hash_map::DefaultHasher::new();
+                    arg.hash(&mut hasher);
+                    hasher.finish()
+                }
+            },
+            // Returns -1, 0 or 1 for less, equal or greater, and 2 if the
+            // values can't be compared.
+            "PartialOrd" => quote! {
+                #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+                #[no_mangle]
+                pub extern "C" fn #cmp_name(arg: *const #ty, other: *const #ty) -> i8 {
+                    ffi_ref!(@ref arg);
+                    ffi_ref!(@ref other);
+                    match arg.partial_cmp(other) {
+                        Some(::std::cmp::Ordering::Less) => -1,
+                        Some(::std::cmp::Ordering::Equal) => 0,
+                        Some(::std::cmp::Ordering::Greater) => 1,
+                        None => 2,
+                    }
+                }
+            },
+            _ => unreachable!("unknown derives are rejected by `get_derives`"),
+        }
+    });
+    tokens.append_all(new_toks);
+    tokens
+}
+
 /// A method definition inside an impl block has an additional
 /// `base` variable corresponding to the name of the type.
+/// Generic functions are exported once per `instance`, with the signature
+/// in `fn_def` already specialised to the concrete types.
 struct InternalFn<'a> {
     base: &'a Option<syn::Ident>,
+    /// The trait the method implements, for methods of trait impls.
+    trait_: Option<&'a syn::Path>,
     fn_def: &'a syn::ItemFn,
+    instance: Option<&'a Instance>,
+    /// Whether this is an `async fn`, which `fn_def` was parsed without.
+    asyncness: bool,
+}
+
+impl<'a> InternalFn<'a> {
+    /// Concrete types for the function's own generic parameters, in the
+    /// order they are declared.
+    fn fn_generics(&self) -> Vec<syn::Type> {
+        match self.instance {
+            None => vec![],
+            Some(inst) => self.fn_def.decl.generics.type_params().map(|tp| {
+                inst.get(&tp.ident).cloned()
+                    .unwrap_or_else(|| panic!("No type given for generic parameter `{}`", tp.ident))
+            }).collect(),
+        }
+    }
+
+    /// Concrete types for the base type's generic parameters, which is
+    /// anything in the instance not belonging to the function itself.
+    fn base_generics(&self) -> Vec<syn::Type> {
+        match self.instance {
+            None => vec![],
+            Some(inst) => {
+                let own: Vec<&syn::Ident> = self.fn_def.decl.generics.type_params().map(|tp| &tp.ident).collect();
+                inst.0.iter().filter(|(id, _)| !own.contains(&id)).map(|(_, ty)| ty.clone()).collect()
+            },
+        }
+    }
+
+    /// The full base type, e.g. `Foo` or `Stack<u32>`.
+    fn base_ty(&self) -> Option<syn::Type> {
+        let args = self.base_generics();
+        self.base.as_ref().map(|base| {
+            if args.is_empty() {
+                parse_quote!(#base)
+            } else {
+                parse_quote!(#base<#(#args),*>)
+            }
+        })
+    }
+
+    /// Name of the SWIG wrapper class for the base type, e.g. `StackU32`.
+    fn base_class(&self) -> Option<String> {
+        self.base.as_ref().map(|base| base.to_string() + &class_suffix(&self.base_generics()))
+    }
+
+    /// Name of the SWIG wrapper class for an opaque type.
+    fn swig_class(&self, ty: &syn::Type) -> String {
+        if type_name(ty) == "Self" {
+            self.base_class().expect("Cannot convert `Self` return type without provided base name.
+                    Try: `#[swiggen(Foo)]` in macro")
+        } else {
+            swig_class_name(ty)
+        }
+    }
+
+    /// Name of the function as seen from SWIG. Generic functions get the
+    /// concrete types as a suffix, e.g. `largest_u32`.
+    fn swig_name(&self) -> String {
+        let name = SwigOptions::from_attrs(&self.fn_def.attrs).name
+            .unwrap_or_else(|| self.fn_def.ident.to_string());
+        let fn_generics = self.fn_generics();
+        if fn_generics.is_empty() {
+            name
+        } else {
+            format!("{}_{}", name, fn_suffix(&fn_generics))
+        }
+    }
+
+    /// Unique name for the generated items. Methods are qualified with the
+    /// class name, so that different types can have methods of the same name,
+    /// and trait methods with the trait, e.g. `Foo_From_u32_from`.
+    fn mangled_name(&self) -> syn::Ident {
+        let name = match (self.base_class(), self.trait_ty()) {
+            (Some(base), Some(trait_)) => format!("{}_{}_{}", base, type_suffix(&trait_), self.swig_name()),
+            (Some(base), None) => format!("{}_{}", base, self.swig_name()),
+            (None, _) => self.swig_name(),
+        };
+        syn::Ident::new(&name, Span::call_site())
+    }
+
+    /// Name of the C struct a closure argument is passed in as.
+    fn callback_struct(&self, arg: &syn::Ident) -> syn::Ident {
+        syn::Ident::new(&format!("SwigCallback_{}_{}", self.mangled_name(), arg), Span::call_site())
+    }
+
+    /// Name of the opaque handle an iterator is returned as.
+    fn iter_handle(&self) -> syn::Ident {
+        syn::Ident::new(&format!("SwigIter_{}", self.mangled_name()), Span::call_site())
+    }
+
+    /// Name of the opaque handle the result of an awaitable function is
+    /// returned as, until its future completes.
+    fn task_handle(&self) -> syn::Ident {
+        syn::Ident::new(&format!("SwigTask_{}", self.mangled_name()), Span::call_site())
+    }
+
+    /// Index of the first parameter (not counting `self`) which can be left
+    /// out, i.e. after which every parameter has a default value or is an
+    /// `Option`.
+    fn optional_from(&self, opts: &SwigOptions) -> usize {
+        let params: Vec<(String, &syn::Type)> = self.fn_def.decl.inputs.iter().filter_map(|arg| match arg {
+            syn::FnArg::Captured(ac) => match &ac.pat {
+                syn::Pat::Ident(pi) => Some((pi.ident.to_string(), &ac.ty)),
+                _ => None,
+            },
+            _ => None,
+        }).collect();
+        for (id, _) in opts.defaults.iter() {
+            match params.iter().find(|(p, _)| p == id) {
+                None => panic!("Cannot give a default value for unknown parameter `{}`", id),
+                Some((_, ty)) => {
+                    let plain = callback_sig(ty).is_none() && dyn_trait(ty).is_none() && match bytes_kind(ty) {
+                        Some(BytesKind::Like) | None => true,
+                        Some(_) => false,
+                    };
+                    if !plain {
+                        panic!("Cannot give a default value for `{}`", id);
+                    }
+                },
+            }
+        }
+        let mut from = params.len();
+        while from > 0 && (opts.default_of(&params[from - 1].0).is_some() || option_inner(params[from - 1].1).is_some()) {
+            from -= 1;
+        }
+        if let Some((id, _)) = params[..from].iter().find(|(id, _)| opts.default_of(id).is_some()) {
+            panic!("Parameter `{}` has a default value, so the parameters after it need one too", id);
+        }
+        from
+    }
+
+    /// The implemented trait, with the concrete types of the instance.
+    fn trait_ty(&self) -> Option<syn::Type> {
+        self.trait_.map(|path| {
+            let tokens = match self.instance {
+                Some(inst) => substitute(path.into_token_stream(), &inst.0),
+                None => path.into_token_stream(),
+            };
+            syn::parse2(tokens).expect("Invalid trait path")
+        })
+    }
+
+    /// Whether this is a method taking `self`.
+    fn has_receiver(&self) -> bool {
+        self.fn_def.decl.inputs.iter().any(|arg| matches!(arg, syn::FnArg::SelfRef(_) | syn::FnArg::SelfValue(_)))
+    }
+
+    /// Whether this is a method taking `&mut self` or `self`.
+    fn mutates_receiver(&self) -> bool {
+        self.fn_def.decl.inputs.iter().any(|arg| match arg {
+            syn::FnArg::SelfRef(sr) => sr.mutability.is_some(),
+            syn::FnArg::SelfValue(_) => true,
+            _ => false,
+        })
+    }
+
+    /// Path used to call the Rust function, e.g. `<Stack<u32>>::push`, or
+    /// `<Foo as From<u32>>::from` for trait methods.
+    fn callee(&self) -> TokenStream {
+        let name = &self.fn_def.ident;
+        let fn_generics = self.fn_generics();
+        let turbofish = if fn_generics.is_empty() {
+            quote!{}
+        } else {
+            quote!{::<#(#fn_generics),*>}
+        };
+        match (self.base_ty(), self.trait_ty()) {
+            (Some(base), Some(trait_)) => quote!{<#base as #trait_>::#name #turbofish},
+            (Some(base), None) => quote!{<#base>::#name #turbofish},
+            (None, _) => quote!{#name #turbofish},
+        }
+    }
+}
+
+/// Turn a type into something usable in an identifier, e.g. `Vec<u8>`
+/// becomes `Vec_u8`.
+fn type_suffix(ty: &syn::Type) -> String {
+    type_name(ty).split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// Suffix for instantiated functions, e.g. `u32_f64`.
+fn fn_suffix(tys: &[syn::Type]) -> String {
+    tys.iter().map(type_suffix).collect::<Vec<_>>().join("_")
+}
+
+/// Suffix for instantiated classes in CamelCase, e.g. `U32F64`.
+fn class_suffix(tys: &[syn::Type]) -> String {
+    tys.iter().flat_map(|ty| {
+        type_suffix(ty).split('_').map(|part| {
+            let mut chars = part.chars();
+            chars.next().map_or(String::new(), |c| c.to_uppercase().collect::<String>() + chars.as_str())
+        }).collect::<Vec<_>>()
+    }).collect()
+}
+
+/// Name of the SWIG wrapper class for an opaque type: the type name,
+/// followed by the suffix for any generic arguments (`Stack<u32>` is
+/// wrapped by `StackU32`).
+fn swig_class_name(ty: &syn::Type) -> String {
+    match ty {
+        syn::Type::Path(tp) => {
+            let seg = tp.path.segments.iter().last().expect("empty type path");
+            let args: Vec<syn::Type> = match seg.arguments {
+                syn::PathArguments::AngleBracketed(ref ab) => ab.args.iter().filter_map(|a| {
+                    match a {
+                        syn::GenericArgument::Type(t) => Some(t.clone()),
+                        _ => None,
+                    }
+                }).collect(),
+                _ => vec![],
+            };
+            seg.ident.to_string() + &class_suffix(&args)
+        },
+        _ => type_name(ty),
+    }
+}
+
+/// Name of an opaque type in the cbindgen-generated bindings.
+fn ffi_class_name(ty: &syn::Type) -> String {
+    match ty {
+        syn::Type::Path(tp) if matches!(tp.path.segments.iter().last(), Some(s) if s.arguments.is_empty()) => {
+            tp.path.segments.iter().last().unwrap().ident.to_string()
+        },
+        _ => cbindgen_write(&cbindgen_type(ty).expect("Cannot use `()` as a class")),
+    }
+}
+
+/// Replace any generic parameters (or `Self`) in a token stream with their
+/// concrete types.
+fn substitute(tokens: TokenStream, bindings: &[(syn::Ident, syn::Type)]) -> TokenStream {
+    tokens.into_iter().flat_map(|tt| -> Vec<TokenTree> {
+        match tt {
+            TokenTree::Ident(ref id) => match bindings.iter().find(|(param, _)| param == id) {
+                Some((_, ty)) => ty.into_token_stream().into_iter().collect(),
+                None => vec![tt.clone()],
+            },
+            TokenTree::Group(ref g) => {
+                let mut group = Group::new(g.delimiter(), substitute(g.stream(), bindings));
+                group.set_span(g.span());
+                vec![TokenTree::Group(group)]
+            },
+            _ => vec![tt.clone()],
+        }
+    }).collect()
+}
+
+/// Specialise the signature of a generic function to the types in `inst`.
+/// When the base type is generic, `Self` is replaced with `self_ty` too.
+fn monomorphize(fn_def: &syn::ItemFn, inst: &Instance, self_ty: Option<syn::Type>) -> syn::ItemFn {
+    let mut bindings = inst.0.clone();
+    if let Some(self_ty) = self_ty {
+        bindings.push((syn::Ident::new("Self", Span::call_site()), self_ty));
+    }
+    let mut fn_def = fn_def.clone();
+    for arg in fn_def.decl.inputs.iter_mut() {
+        if let syn::FnArg::Captured(ref mut ac) = arg {
+            ac.ty = syn::parse2(substitute(ac.ty.clone().into_token_stream(), &bindings))
+                .expect("failed to substitute generic types");
+        }
+    }
+    if let syn::ReturnType::Type(_, ref mut ty) = fn_def.decl.output {
+        **ty = syn::parse2(substitute(ty.clone().into_token_stream(), &bindings))
+            .expect("failed to substitute generic types");
+    }
+    fn_def
 }
 
 /// Convenience method to use cbindgen to convert types into C-compat types.
@@ -150,19 +572,29 @@ fn cbindgen_write<S: Source>(s: &S) -> String {
     String::from_utf8(buf).unwrap().replace("str", "char")
 }
 
+/// Load a single Rust type with cbindgen, so it can be written out as C.
+fn cbindgen_type(ty: &syn::Type) -> Option<ty::Type> {
+    match ty::Type::load(ty) {
+        Ok(t) => t,
+        Err(e) => panic!("Cannot convert type `{}`: {}", ty.clone().into_token_stream(), e),
+    }
+}
+
 /// Hacky method to take a `&self` or `self` function argument and produce
 /// something compatible with `extern "C"` method. Since we can't use `self`, 
 /// we coerce this to a pointer, and call the arg `wrapped_self`.
-fn convert_self_type(arg: &syn::FnArg, base: &Option<syn::Ident>) -> syn::FnArg {
+fn convert_self_type(arg: &syn::FnArg, base: &Option<syn::Type>) -> syn::FnArg {
     let base = base.clone().expect("Cannot convert `self` arg without provided base name.
                             Try: `#[swiggen(Foo)]` in macro");
     let mut arg = arg.clone().into_token_stream().to_string();
-    arg = if arg.starts_with('&') {
+    arg = if arg.starts_with("& mut") {
+        arg.replacen("& mut", "*mut ", 1)
+    } else if arg.starts_with('&') {
         arg.replace("&", "*const ")
     } else {
         "*mut ".to_string() + &arg
     };
-    arg = format!("wrapped_self: {}", arg.replace("self", &base.to_string()));
+    arg = format!("wrapped_self: {}", arg.replace("self", &base.into_token_stream().to_string()));
     syn::parse_str(&arg).unwrap()
 }
 
@@ -182,27 +614,180 @@ fn convert_arg_type(syn::ArgCaptured { ref pat, ref ty, .. }: &syn::ArgCaptured)
 
 /// Similar to above, make sure that we return primitives when 
 /// recognised 
-fn convert_ret_type(rty: &syn::ReturnType, base: &Option<syn::Ident>) -> syn::ReturnType {
+fn convert_ret_type(rty: &syn::ReturnType, base: &Option<syn::Type>) -> syn::ReturnType {
     match rty {
         syn::ReturnType::Default => syn::ReturnType::Default,
+        // Tuples are passed back through out-parameters instead.
+        syn::ReturnType::Type(_, ty) if tuple_elems(ty).is_some() => syn::ReturnType::Default,
+        // Static strings are returned like borrowed ones, as pointer + length.
+        syn::ReturnType::Type(_, ty) if str_ret_kind(ty) == Some(StrRet::Static) => parse_quote!(-> *const c_char),
         syn::ReturnType::Type(_, ty) => {
-            if needs_ref(ty) {
-                if ty.clone().into_token_stream().to_string() == "Self" {
-                    let base = base.clone().expect("Cannot convert `Self` return type without provided base name.
-                            Try: `#[swiggen(Foo)]` in macro");
-                    parse_quote!(-> *mut #base)
-                } else if ty.clone().into_token_stream().to_string() == "String" {
-                    parse_quote!(-> *mut c_char)
-                } else {
-                    parse_quote!(-> *mut #ty)
-                }
-            } else {
-                parse_quote!(-> #ty)
-            }
+            let ty = convert_ret_ty(ty, base);
+            parse_quote!(-> #ty)
+        }
+    }
+}
+
+/// Convert a single returned type into its `extern "C"` equivalent.
+fn convert_ret_ty(ty: &syn::Type, base: &Option<syn::Type>) -> syn::Type {
+    match str_ret_kind(ty) {
+        Some(StrRet::Owned) | Some(StrRet::Static) => return parse_quote!(*mut c_char),
+        Some(StrRet::Borrowed) => return parse_quote!(*const c_char),
+        None => {},
+    }
+    match bytes_kind(ty) {
+        Some(BytesKind::Owned) | Some(BytesKind::Like) => return parse_quote!(*mut u8),
+        Some(_) => return parse_quote!(*const u8),
+        None => {},
+    }
+    if let Some((_, inner)) = shared_inner(ty) {
+        return convert_ret_ty(inner, base);
+    }
+    if let Some(inner) = borrowed_class(ty) {
+        return convert_ret_ty(inner, base);
+    }
+    if needs_ref(ty) {
+        if ty.clone().into_token_stream().to_string() == "Self" {
+            let base = base.clone().expect("Cannot convert `Self` return type without provided base name.
+                    Try: `#[swiggen(Foo)]` in macro");
+            parse_quote!(*mut #base)
+        } else {
+            parse_quote!(*mut #ty)
+        }
+    } else {
+        ty.clone()
+    }
+}
+
+/// If this is a non-empty tuple type, get the types of its elements.
+fn tuple_elems(ty: &syn::Type) -> Option<Vec<&syn::Type>> {
+    match ty {
+        syn::Type::Tuple(tt) if !tt.elems.is_empty() => Some(tt.elems.iter().collect()),
+        syn::Type::Paren(tp) => tuple_elems(&tp.elem),
+        _ => None,
+    }
+}
+
+/// Reference counted pointers, which are handed out as a strong reference
+/// shared with Rust rather than boxed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Shared {
+    Arc,
+    Rc,
+}
+
+impl Shared {
+    /// The matching `swig_handle` of the wrapper class.
+    fn swig_handle(&self) -> &'static str {
+        match self {
+            Shared::Arc => "SWIG_ARC",
+            Shared::Rc => "SWIG_RC",
         }
     }
 }
 
+/// The pointer kind and type of an `Arc<Foo>` or `Rc<Foo>` of a wrapper class.
+fn shared_inner(ty: &syn::Type) -> Option<(Shared, &syn::Type)> {
+    let tp = match ty {
+        syn::Type::Path(tp) => tp,
+        _ => return None,
+    };
+    let seg = tp.path.segments.iter().last()?;
+    let kind = match seg.ident.to_string().as_str() {
+        "Arc" => Shared::Arc,
+        "Rc" => Shared::Rc,
+        _ => return None,
+    };
+    match seg.arguments {
+        syn::PathArguments::AngleBracketed(ref ab) if ab.args.len() == 1 => match ab.args.iter().next() {
+            Some(syn::GenericArgument::Type(inner)) if needs_ref(inner) => Some((kind, inner)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The wrapper class type of a `&Foo` or `&mut Foo`.
+fn borrowed_class(ty: &syn::Type) -> Option<&syn::Type> {
+    match ty {
+        syn::Type::Reference(r) if needs_ref(&r.elem) && str_ret_kind(ty).is_none() && bytes_kind(ty).is_none() => Some(&r.elem),
+        _ => None,
+    }
+}
+
+/// Whether this is a `&mut T`.
+fn is_mut_ref(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Reference(r) => r.mutability.is_some(),
+        _ => false,
+    }
+}
+
+/// If this is an `Option<T>`, get `T`.
+fn option_inner(ty: &syn::Type) -> Option<&syn::Type> {
+    match ty {
+        syn::Type::Path(tp) => {
+            let seg = tp.path.segments.iter().last()?;
+            if seg.ident != "Option" {
+                return None;
+            }
+            match seg.arguments {
+                syn::PathArguments::AngleBracketed(ref ab) if ab.args.len() == 1 => match ab.args.iter().next() {
+                    Some(syn::GenericArgument::Type(ty)) => Some(ty),
+                    _ => None,
+                },
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// If this is a `Vec<T>`, get `T`.
+fn vec_elem(ty: &syn::Type) -> Option<&syn::Type> {
+    match ty {
+        syn::Type::Path(tp) => {
+            let seg = tp.path.segments.iter().last()?;
+            if seg.ident != "Vec" {
+                return None;
+            }
+            match seg.arguments {
+                syn::PathArguments::AngleBracketed(ref ab) if ab.args.len() == 1 => match ab.args.iter().next() {
+                    Some(syn::GenericArgument::Type(ty)) => Some(ty),
+                    _ => None,
+                },
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Shorthand for the tuple elements of a function's return type.
+fn fn_tuple_ret(rty: &syn::ReturnType) -> Option<Vec<&syn::Type>> {
+    match rty {
+        syn::ReturnType::Type(_, ty) => tuple_elems(ty),
+        syn::ReturnType::Default => None,
+    }
+}
+
+/// Pick the `box_ptr!` conversion for a value returned through a single
+/// pointer, i.e. anything but borrowed strings and byte buffers. Static
+/// strings in tuples and iterators are copied, like a `String`.
+fn box_ptr_conv(ty: &syn::Type, value: TokenStream) -> TokenStream {
+    match str_ret_kind(ty) {
+        Some(StrRet::Owned) | Some(StrRet::Static) => quote!{@str #value},
+        Some(StrRet::Borrowed) => panic!("Cannot return a borrowed `&str` here, try `String` instead"),
+        None if bytes_kind(ty).is_some() => panic!("Cannot return a byte buffer here, try returning it on its own"),
+        None => match shared_inner(ty) {
+            Some((Shared::Arc, _)) => quote!{@arc #value},
+            Some((Shared::Rc, _)) => quote!{@rc #value},
+            None if borrowed_class(ty).is_some() => quote!{@borrowed #value},
+            None if needs_ref(ty) => quote!{#value},
+            None => quote!{@prim #value},
+        },
+    }
+}
 /// For paths, assume we can convert to an opaque pointer.
 fn needs_ref(ty: &syn::Type) -> bool {
     match ty::Type::load(ty) {
@@ -212,156 +797,1029 @@ fn needs_ref(ty: &syn::Type) -> bool {
     }
 }
 
-impl<'a> AsExtern for InternalFn<'a> {
-    fn as_extern(&self) -> TokenStream {
-        // Messy blob of code to convert function name, arguments, types, 
-        // return type and generate appropriate code.
-        // Should be extracted out into smaller functions.
-        let name = &self.fn_def.ident;
-        let ext_name = swig_fn(&name, "ffi");
-        let mut args = Vec::<TokenStream>::new();
-        let mut caller = Vec::<syn::Ident>::new();
-        let mut caller_ref = Vec::<TokenStream>::new();
-        self.fn_def.decl.inputs.iter().for_each(|ref arg| {
-            match arg {
-                syn::FnArg::SelfRef(_) | syn::FnArg::SelfValue(_) => {
-                    // For self methods, we do some extra work to wrap the
-                    // function so that `impl Foo { fn bar(&self); }`
-                    // becomes `Foo_bar(wrapped_self: *const Foo)`.
-                    let wrapped_self = convert_self_type(&arg, self.base);
-                    args.push(wrapped_self.into_token_stream());
-
-                    let ws = syn::Ident::new("wrapped_self", Span::call_site());
-                    caller.push(ws.clone());
-                    caller_ref.push(quote!{@ref #ws});
-                }
-                syn::FnArg::Captured(ref ac) => {
-                    let id = match &ac.pat {
-                        syn::Pat::Ident(pi) => {
-                            &pi.ident
-                        },
-                        _ => unimplemented!(),
-                    };
-                    args.push(convert_arg_type(ac).into_token_stream());
-                    caller.push(id.clone());
+/// The ways a string can be returned across the FFI boundary.
+#[derive(Clone, Copy, PartialEq)]
+enum StrRet {
+    /// `String`: converted into an owned C string, freed by `free_string`.
+    Owned,
+    /// `&'static str`: handed out as pointer + length like a borrowed
+    /// string, but needs no object to borrow from. Never freed.
+    Static,
+    /// `&str` borrowed from an argument (usually `&self`): handed out as
+    /// pointer + length, and copied by the SWIG wrapper immediately.
+    Borrowed,
+}
 
-                    // this later calls the appropriate macro function as to
-                    // whether we need to do some pointer/box stuff
-                    if ac.ty.clone().into_token_stream().to_string().ends_with("str") {
-                        caller_ref.push(quote!{@str #id});
-                    } else if let syn::Type::Reference(_) = ac.ty {
-                        caller_ref.push(quote!{@ref #id});
-                    } else {
-                        caller_ref.push(quote!{@prim #id});
-                    }
-                },
-                _ => ()
+/// Check whether a return type is one of the string types we know how to
+/// return without boxing.
+fn str_ret_kind(ty: &syn::Type) -> Option<StrRet> {
+    match ty {
+        syn::Type::Reference(syn::TypeReference { lifetime, elem, .. }) => {
+            if elem.clone().into_token_stream().to_string() != "str" {
+                return None;
             }
-        });
-        let base = self.base;
-        let out = convert_ret_type(&self.fn_def.decl.output, self.base);
-        // Similar to the above, this later calls the appropriate macro function
-        // as to whether we need to do some pointer/box stuff
-        let res_ref = if let syn::ReturnType::Type(_, ref ty) = self.fn_def.decl.output {
-            if ty.clone().into_token_stream().to_string() == "String" {
-                quote!{@str res}
-            } else if needs_ref(&ty) {
-                quote!{res}
-            } else {
-                quote!{@prim res}
+            match lifetime {
+                Some(lt) if lt.ident == "static" => Some(StrRet::Static),
+                _ => Some(StrRet::Borrowed),
             }
-        } else {
-            quote!{@prim res}
-        };
+        },
+        _ if ty.clone().into_token_stream().to_string() == "String" => Some(StrRet::Owned),
+        _ => None,
+    }
+}
 
-        /// Generate the function. We also inject some macro
-        /// definitions to help with converting pointers into types and types
-        /// into pointers.
-        let tokens = quote! {
-            #[allow(non_snake_case)]
-            #[no_mangle]
-            pub extern "C" fn #ext_name(#(#args),*)  #out {
-                #(ffi_ref!(#caller_ref);)*
-                let res = #base::#name(#(#caller),*);
-                box_ptr!(#res_ref)
-            }
-        };
-        tokens
+/// Shorthand for the `StrRet` of a function's return type.
+fn fn_str_ret(rty: &syn::ReturnType) -> Option<StrRet> {
+    match rty {
+        syn::ReturnType::Type(_, ty) => str_ret_kind(ty),
+        syn::ReturnType::Default => None,
     }
 }
 
+/// The ways a byte buffer can cross the FFI boundary. These are always
+/// passed as a pointer + length, since binary data can't be NUL-terminated.
+#[derive(Clone, Copy, PartialEq)]
+enum BytesKind {
+    /// `&[u8]`: borrowed, copied by the SWIG wrapper when returned.
+    Borrowed,
+    /// `&mut [u8]`: mutably borrowed, only supported for arguments.
+    BorrowedMut,
+    /// `Vec<u8>` or `Box<[u8]>`: owned, released with `free_bytes` once
+    /// returned.
+    Owned,
+    /// Other bytes-like types such as `bytes::Bytes`. Only supported for
+    /// returns, where the contents are copied into an owned buffer.
+    Like,
+}
 
-/// Helper function to define the exported/mangled names.
-fn swig_fn(name: &syn::Ident, fn_name: &str) -> syn::Ident {
-    syn::Ident::new(&format!("{}{}_{}", SwigTag::SwigInject, fn_name, name), Span::call_site())
+/// Token representation of a type, with whitespace removed so it can be
+/// compared against strings like `"Vec<u8>"`.
+fn type_name(ty: &syn::Type) -> String {
+    ty.clone().into_token_stream().to_string().replace(" ", "")
 }
 
-fn swig_free(name: &syn::Ident) -> syn::Ident {
-    swig_fn(name, "free")
+/// Check whether a type is a byte buffer.
+fn bytes_kind(ty: &syn::Type) -> Option<BytesKind> {
+    match ty {
+        syn::Type::Reference(syn::TypeReference { mutability, elem, .. }) => {
+            if type_name(elem) != "[u8]" {
+                None
+            } else if mutability.is_some() {
+                Some(BytesKind::BorrowedMut)
+            } else {
+                Some(BytesKind::Borrowed)
+            }
+        },
+        _ => {
+            let name = type_name(ty);
+            if name == "Vec<u8>" || name == "Box<[u8]>" {
+                Some(BytesKind::Owned)
+            } else if name == "Bytes" || name.ends_with("::Bytes") {
+                Some(BytesKind::Like)
+            } else {
+                None
+            }
+        },
+    }
 }
 
-impl ToSwig for syn::DeriveInput {
-    fn to_swig(&self) -> String {
-        /// Generate the SWIG wrapper code as a string.
-        /// Basically, a class for the Rust struct `Foo` is just a wrapper
-        /// class called `Foo` which contains a pointer to the actual Rust
-        /// object.
+/// How a trait object is passed in to Rust.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DynKind {
+    /// `Box<dyn Foo>`, ownership is passed to Rust.
+    Boxed,
+    /// `&dyn Foo`
+    Borrowed,
+    /// `&mut dyn Foo`
+    BorrowedMut,
+}
+
+type Bounds = syn::punctuated::Punctuated<syn::TypeParamBound, Token![+]>;
+
+/// Check whether a type is a trait object, returning its bounds.
+fn dyn_bounds(ty: &syn::Type) -> Option<(DynKind, &Bounds)> {
+    fn bounds(ty: &syn::Type) -> Option<&Bounds> {
+        match ty {
+            syn::Type::TraitObject(syn::TypeTraitObject { bounds, .. }) => Some(bounds),
+            _ => None,
+        }
+    }
+    match ty {
+        syn::Type::Reference(syn::TypeReference { mutability, elem, .. }) => {
+            let kind = if mutability.is_some() { DynKind::BorrowedMut } else { DynKind::Borrowed };
+            bounds(elem).map(|b| (kind, b))
+        },
+        syn::Type::Path(tp) => {
+            let seg = tp.path.segments.iter().last()?;
+            if seg.ident != "Box" {
+                return None;
+            }
+            match seg.arguments {
+                syn::PathArguments::AngleBracketed(ref ab) => match ab.args.iter().next() {
+                    Some(syn::GenericArgument::Type(ty)) => bounds(ty).map(|b| (DynKind::Boxed, b)),
+                    _ => None,
+                },
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Check whether a type is a trait object, returning the name of the trait.
+fn dyn_trait(ty: &syn::Type) -> Option<(DynKind, syn::Ident)> {
+    let (kind, bounds) = dyn_bounds(ty)?;
+    bounds.iter().filter_map(|b| match b {
+        syn::TypeParamBound::Trait(tb) => tb.path.segments.iter().last().map(|s| (kind, s.ident.clone())),
+        _ => None,
+    }).next()
+}
+
+/// A closure argument, passed in from the target language as a function
+/// pointer with some `user_data`.
+struct Callback {
+    /// How the closure is passed to Rust, `None` for `impl Fn(..)`.
+    kind: Option<DynKind>,
+    inputs: Vec<syn::Type>,
+    output: Option<syn::Type>,
+}
+
+/// Check whether a type is a closure: `impl Fn(..)`, `Box<dyn Fn(..)>`,
+/// `&dyn Fn(..)` or `&mut dyn FnMut(..)`.
+fn callback_sig(ty: &syn::Type) -> Option<Callback> {
+    fn fn_bound(bounds: &Bounds) -> Option<(Vec<syn::Type>, Option<syn::Type>)> {
+        bounds.iter().filter_map(|b| match b {
+            syn::TypeParamBound::Trait(tb) => tb.path.segments.iter().last(),
+            _ => None,
+        }).filter(|seg| {
+            let name = seg.ident.to_string();
+            name == "Fn" || name == "FnMut" || name == "FnOnce"
+        }).filter_map(|seg| match seg.arguments {
+            syn::PathArguments::Parenthesized(ref pa) => {
+                let output = match pa.output {
+                    syn::ReturnType::Type(_, ref ty) if type_name(ty) != "()" => Some((**ty).clone()),
+                    _ => None,
+                };
+                Some((pa.inputs.iter().cloned().collect(), output))
+            },
+            _ => None,
+        }).next()
+    }
+    let (kind, (inputs, output)) = match ty {
+        syn::Type::ImplTrait(syn::TypeImplTrait { bounds, .. }) => (None, fn_bound(bounds)?),
+        _ => {
+            let (kind, bounds) = dyn_bounds(ty)?;
+            (Some(kind), fn_bound(bounds)?)
+        },
+    };
+    Some(Callback { kind, inputs, output })
+}
+
+/// Check whether a type is an iterator, returning the type of its items.
+/// Supports `impl Iterator<Item = T>`, `Box<dyn Iterator<Item = T>>` and
+/// the `IntoIter<T>` types of the standard collections.
+fn iter_item(ty: &syn::Type) -> Option<syn::Type> {
+    fn item_binding(bounds: &Bounds) -> Option<syn::Type> {
+        bounds.iter().filter_map(|b| match b {
+            syn::TypeParamBound::Trait(tb) => tb.path.segments.iter().last(),
+            _ => None,
+        }).filter(|seg| seg.ident.to_string().ends_with("Iterator"))
+        .filter_map(|seg| match seg.arguments {
+            syn::PathArguments::AngleBracketed(ref ab) => ab.args.iter().filter_map(|a| match a {
+                syn::GenericArgument::Binding(b) if b.ident == "Item" => Some(b.ty.clone()),
+                _ => None,
+            }).next(),
+            _ => None,
+        }).next()
+    }
+    match ty {
+        syn::Type::ImplTrait(syn::TypeImplTrait { bounds, .. }) => item_binding(bounds),
+        syn::Type::Path(tp) => {
+            if let Some((DynKind::Boxed, bounds)) = dyn_bounds(ty) {
+                return item_binding(bounds);
+            }
+            let seg = tp.path.segments.iter().last()?;
+            if seg.ident != "IntoIter" {
+                return None;
+            }
+            match seg.arguments {
+                syn::PathArguments::AngleBracketed(ref ab) if ab.args.len() == 1 => match ab.args.iter().next() {
+                    Some(syn::GenericArgument::Type(ty)) => Some(ty.clone()),
+                    _ => None,
+                },
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Shorthand for the item type of a function returning an iterator.
+fn fn_iter_ret(rty: &syn::ReturnType) -> Option<syn::Type> {
+    match rty {
+        syn::ReturnType::Type(_, ty) => iter_item(ty),
+        syn::ReturnType::Default => None,
+    }
+}
+
+/// Whether a type mentions a lifetime other than `'static`, including elided
+/// ones in references.
+fn has_lifetime(ty: &syn::Type) -> bool {
+    fn flatten(tokens: TokenStream, out: &mut Vec<TokenTree>) {
+        for tt in tokens {
+            match tt {
+                TokenTree::Group(g) => flatten(g.stream(), out),
+                tt => out.push(tt),
+            }
+        }
+    }
+    let mut tokens = Vec::new();
+    flatten(ty.into_token_stream(), &mut tokens);
+    let is_static = |i: usize| match (tokens.get(i), tokens.get(i + 1)) {
+        (Some(TokenTree::Punct(p)), Some(TokenTree::Ident(id))) => p.as_char() == '\'' && id == "static",
+        _ => false,
+    };
+    (0..tokens.len()).any(|i| match tokens[i] {
+        TokenTree::Punct(ref p) if p.as_char() == '&' => !is_static(i + 1),
+        TokenTree::Punct(ref p) if p.as_char() == '\'' => !is_static(i),
+        _ => false,
+    })
+}
+
+/// How a primitive is passed between C++ and Python in callback glue.
+/// Returns the `Py_BuildValue` format, the C++ expression building it from
+/// `arg`, and the expression converting the Python object `obj` back.
+fn py_conv(ty: &syn::Type, arg: &str, obj: &str) -> (&'static str, String, String) {
+    match type_name(ty).as_str() {
+        "bool" => ("O", format!("{} ? Py_True : Py_False", arg), format!("PyObject_IsTrue({}) == 1", obj)),
+        "i8" | "i16" | "i32" => ("i", format!("(int) {}", arg), format!("PyLong_AsLong({})", obj)),
+        "u8" | "u16" | "u32" => ("I", format!("(unsigned int) {}", arg), format!("PyLong_AsUnsignedLong({})", obj)),
+        "i64" | "isize" => ("L", format!("(long long) {}", arg), format!("PyLong_AsLongLong({})", obj)),
+        "u64" | "usize" => ("K", format!("(unsigned long long) {}", arg), format!("PyLong_AsUnsignedLongLong({})", obj)),
+        "f32" | "f64" => ("d", format!("(double) {}", arg), format!("PyFloat_AsDouble({})", obj)),
+        other => panic!("Unsupported type `{}` in callback, only primitive types can be used", other),
+    }
+}
+
+/// Name of the C vtable generated for a `#[swiggen]` trait.
+fn trait_vtable(name: &syn::Ident) -> syn::Ident {
+    syn::Ident::new(&format!("{}VTable", name), Span::call_site())
+}
+
+/// Name of the Rust adapter implementing a `#[swiggen]` trait over its vtable.
+fn trait_adapter(name: &syn::Ident) -> syn::Ident {
+    syn::Ident::new(&format!("{}Adapter", name), Span::call_site())
+}
+
+/// The name of a function argument. The SWIG wrappers pass arguments on by
+/// name, so patterns can't be used.
+fn arg_ident<'a>(func: &syn::Ident, arg: &'a syn::ArgCaptured) -> &'a syn::Ident {
+    match arg.pat {
+        syn::Pat::Ident(ref pi) => &pi.ident,
+        ref pat => panic!("Cannot export `{}`: use a plain name instead of the pattern `{}` as argument",
+                          func, pat.clone().into_token_stream()),
+    }
+}
+
+/// Shorthand for the `BytesKind` of a function's return type.
+fn fn_bytes_ret(rty: &syn::ReturnType) -> Option<BytesKind> {
+    match rty {
+        syn::ReturnType::Type(_, ty) => bytes_kind(ty),
+        syn::ReturnType::Default => None,
+    }
+}
+
+/// A single element of a tuple return, as seen by the SWIG wrapper.
+struct SwigTupleElem {
+    /// C type of the out-parameter passed to the extern.
+    ffi_ty: String,
+    /// Type of the field in the generated result struct.
+    field_ty: String,
+    /// Expression converting the out-parameter into the field.
+    conv: String,
+    /// Whether the field is an owned pointer to a wrapper class.
+    is_class: bool,
+}
+
+/// Work out how to pass the `i`th element of a tuple return through SWIG.
+fn swig_tuple_elem(ifn: &InternalFn, ty: &syn::Type, i: usize) -> SwigTupleElem {
+    let out = format!("swig_out_{}", i);
+    match str_ret_kind(ty) {
+        Some(StrRet::Owned) | Some(StrRet::Static) => SwigTupleElem {
+            ffi_ty: "char *".to_string(),
+            field_ty: "std::string".to_string(),
+            conv: format!("swig_take_string({})", out),
+            is_class: false,
+        },
+        Some(StrRet::Borrowed) => panic!("Cannot return a borrowed `&str` in a tuple, try `String` instead"),
+        None if borrowed_class(ty).is_some() => {
+            panic!("Cannot return the reference `{}` in a tuple or iterator", ty.clone().into_token_stream())
+        },
+        None if shared_inner(ty).is_some() => {
+            let (kind, inner) = shared_inner(ty).unwrap();
+            let elem = swig_tuple_elem(ifn, inner, i);
+            SwigTupleElem {
+                conv: format!("new {}({}, {})", ifn.swig_class(inner), out, kind.swig_handle()),
+                ..elem
+            }
+        },
+        None if needs_ref(ty) => {
+            let class = ifn.swig_class(ty);
+            let ffi_class = if type_name(ty) == "Self" { class.clone() } else { ffi_class_name(ty) };
+            SwigTupleElem {
+                ffi_ty: format!("ffi::{} *", ffi_class),
+                field_ty: format!("{} *", class),
+                conv: format!("new {}({})", class, out),
+                is_class: true,
+            }
+        },
+        None => {
+            let c_ty = cbindgen_write(&cbindgen_type(ty).expect("Cannot return `()` in a tuple"));
+            SwigTupleElem {
+                ffi_ty: c_ty.clone(),
+                field_ty: c_ty,
+                conv: out,
+                is_class: false,
+            }
+        },
+    }
+}
+
+/// Ruby C API expression converting `expr`, a field of type `field_ty` of a
+/// tuple result struct, into a `VALUE`.
+fn ruby_value(field_ty: &str, expr: &str) -> String {
+    match field_ty {
+        "bool" => format!("({}) ? Qtrue : Qfalse", expr),
+        "float" | "double" => format!("rb_float_new((double) {})", expr),
+        "std::string" => format!("rb_str_new({e}.data(), {e}.size())", e=expr),
+        _ if field_ty.ends_with('*') => {
+            format!("SWIG_NewPointerObj(SWIG_as_voidptr({}), $descriptor({}), SWIG_POINTER_OWN)", expr, field_ty)
+        },
+        _ if field_ty.starts_with('u') || field_ty == "size_t" => format!("ULL2NUM((unsigned long long) {})", expr),
+        _ => format!("LL2NUM((long long) {})", expr),
+    }
+}
+
+impl<'a> AsExtern for InternalFn<'a> {
+    fn as_extern(&self) -> TokenStream {
+        // Messy blob of code to convert function name, arguments, types, 
+        // return type and generate appropriate code.
+        // Should be extracted out into smaller functions.
+        let ext_name = swig_fn(&self.mangled_name(), "ffi");
+        let base_ty = self.base_ty();
+        let mut args = Vec::<TokenStream>::new();
+        let mut caller = Vec::<syn::Ident>::new();
+        let mut caller_ref = Vec::<TokenStream>::new();
+        let mut callbacks = Vec::<TokenStream>::new();
+        self.fn_def.decl.inputs.iter().for_each(|ref arg| {
+            match arg {
+                syn::FnArg::SelfRef(_) | syn::FnArg::SelfValue(_) => {
+                    // For self methods, we do some extra work to wrap the
+                    // function so that `impl Foo { fn bar(&self); }`
+                    // becomes `Foo_bar(wrapped_self: *const Foo)`.
+                    let wrapped_self = convert_self_type(arg, &base_ty);
+                    args.push(wrapped_self.into_token_stream());
+
+                    let ws = syn::Ident::new("wrapped_self", Span::call_site());
+                    caller.push(ws.clone());
+                    match arg {
+                        syn::FnArg::SelfRef(sr) if sr.mutability.is_some() => caller_ref.push(quote!{@mut #ws}),
+                        _ => caller_ref.push(quote!{@ref #ws}),
+                    }
+                }
+                syn::FnArg::Captured(ref ac) => {
+                    let id = arg_ident(&self.fn_def.ident, ac);
+                    caller.push(id.clone());
+
+                    // Closures are passed in as a function pointer with some
+                    // `user_data`, which is released when the closure is dropped.
+                    if let Some(cb) = callback_sig(&ac.ty) {
+                        let cb_name = self.callback_struct(id);
+                        let inputs = &cb.inputs;
+                        let ret_sig = cb.output.as_ref().map(|ty| quote!{-> #ty});
+                        let ret = cb.output.as_ref().map_or(quote!{()}, |ty| ty.into_token_stream());
+                        callbacks.push(quote!{
+                            #[repr(C)]
+                            #[allow(non_camel_case_types)]
+                            pub struct #cb_name {
+                                pub user_data: *mut ::std::os::raw::c_void,
+                                pub call: extern "C" fn(*mut ::std::os::raw::c_void, #(#inputs),*) #ret_sig,
+                                pub drop: extern "C" fn(*mut ::std::os::raw::c_void),
+                            }
+                        });
+                        args.push(quote!{#id: #cb_name});
+
+                        let arg_ids: Vec<syn::Ident> = (0..inputs.len())
+                            .map(|i| syn::Ident::new(&format!("swig_a{}", i), Span::call_site()))
+                            .collect();
+                        let sig = quote!{(#(#arg_ids: #inputs),*) -> #ret};
+                        caller_ref.push(match cb.kind {
+                            None => quote!{@fn #id #sig},
+                            Some(DynKind::Boxed) => quote!{@box_fn #id #sig},
+                            Some(DynKind::Borrowed) => quote!{@ref_fn #id #sig},
+                            Some(DynKind::BorrowedMut) => quote!{@mut_fn #id #sig},
+                        });
+                        return;
+                    }
+
+                    // Optional primitives and strings are passed in as a
+                    // pointer, which is null for `None`.
+                    if let Some(inner) = option_inner(&ac.ty) {
+                        match str_ret_kind(inner) {
+                            Some(StrRet::Owned) => {
+                                args.push(quote!{#id: *const c_char});
+                                caller_ref.push(quote!{@opt_string #id});
+                            },
+                            Some(_) => {
+                                args.push(quote!{#id: *const c_char});
+                                caller_ref.push(quote!{@opt_str #id});
+                            },
+                            None => match cbindgen_type(inner) {
+                                Some(ty::Type::Primitive(_)) => {
+                                    args.push(quote!{#id: *const #inner});
+                                    caller_ref.push(quote!{@opt #id});
+                                },
+                                _ => panic!("Only primitives and strings can be taken as an `Option`"),
+                            },
+                        }
+                        return;
+                    }
+
+                    // Trait objects are passed in as a vtable, and wrapped
+                    // in the adapter for the trait.
+                    if let Some((kind, name)) = dyn_trait(&ac.ty) {
+                        let vtable = trait_vtable(&name);
+                        let adapter = trait_adapter(&name);
+                        args.push(quote!{#id: #vtable});
+                        caller_ref.push(match kind {
+                            DynKind::Boxed => quote!{@dyn_box #id, #adapter},
+                            DynKind::Borrowed => quote!{@dyn_ref #id, #adapter},
+                            DynKind::BorrowedMut => quote!{@dyn_mut #id, #adapter},
+                        });
+                        return;
+                    }
+
+                    // Byte buffers are split into a pointer and a length.
+                    match bytes_kind(&ac.ty) {
+                        Some(BytesKind::Like) | None => {},
+                        Some(bk) => {
+                            let len = syn::Ident::new(&format!("{}_len", id), Span::call_site());
+                            match bk {
+                                BytesKind::Borrowed => {
+                                    args.push(quote!{#id: *const u8, #len: usize});
+                                    caller_ref.push(quote!{@bytes #id, #len});
+                                },
+                                BytesKind::BorrowedMut => {
+                                    args.push(quote!{#id: *mut u8, #len: usize});
+                                    caller_ref.push(quote!{@bytes_mut #id, #len});
+                                },
+                                _ => {
+                                    args.push(quote!{#id: *const u8, #len: usize});
+                                    caller_ref.push(quote!{@bytes_owned #id, #len});
+                                },
+                            }
+                            return;
+                        },
+                    }
+                    // Exported types are borrowed through their handle.
+                    if let Some(inner) = borrowed_class(&ac.ty) {
+                        let inner = match base_ty {
+                            Some(ref base) if type_name(inner) == "Self" => base.clone(),
+                            _ => inner.clone(),
+                        };
+                        if is_mut_ref(&ac.ty) {
+                            args.push(quote!{#id: *mut #inner});
+                            caller_ref.push(quote!{@mut #id});
+                        } else {
+                            args.push(quote!{#id: *const #inner});
+                            caller_ref.push(quote!{@ref #id});
+                        }
+                        return;
+                    }
+                    args.push(convert_arg_type(ac).into_token_stream());
+
+                    // this later calls the appropriate macro function as to
+                    // whether we need to do some pointer/box stuff
+                    if ac.ty.clone().into_token_stream().to_string().ends_with("str") {
+                        caller_ref.push(quote!{@str #id});
+                    } else if let syn::Type::Reference(_) = ac.ty {
+                        caller_ref.push(quote!{@ref #id});
+                    } else {
+                        caller_ref.push(quote!{@prim #id});
+                    }
+                },
+                _ => ()
+            }
+        });
+        let callee = self.callee();
+        let opts = SwigOptions::from_attrs(&self.fn_def.attrs);
+        if opts.awaitable && !self.asyncness {
+            panic!("`#[swig(awaitable)]` needs an `async fn`, which `{}` isn't", self.fn_def.ident);
+        }
+        // Other threads can use the same objects while the GIL is released,
+        // which the prelude only allows if it checks their borrows.
+        let gil_check = if opts.release_gil && caller_ref.iter().any(|conv| {
+            let kind = conv.clone().into_iter().nth(1).map_or(String::new(), |tt| tt.to_string());
+            kind == "ref" || kind == "mut"
+        }) {
+            quote!{ffi_borrow!(@release_gil);}
+        } else {
+            quote!{}
+        };
+        // Awaitable functions pass back their result through another extern.
+        let inputs_len = args.len();
+        let mut out = convert_ret_type(&self.fn_def.decl.output, &base_ty);
+
+        // Iterators are returned as an opaque handle, with externs to take
+        // the next item and to free it.
+        let iter_ret = fn_iter_ret(&self.fn_def.decl.output);
+        let mut iter_externs = TokenStream::new();
+        if let syn::ReturnType::Type(_, ref ty) = self.fn_def.decl.output {
+            // The handle is boxed up as a `'static` trait object, which would
+            // otherwise fail with a lifetime error deep inside the extern.
+            if iter_ret.is_some() && has_lifetime(ty) {
+                panic!("Iterator returned by `{}` borrows from its arguments, but has to be `'static`. \
+                        Try collecting the items into a `Vec` and returning its `into_iter()`", self.fn_def.ident);
+            }
+        }
+        if let Some(ref item) = iter_ret {
+            let handle = self.iter_handle();
+            let next_name = swig_fn(&self.mangled_name(), "next");
+            let free_name = swig_fn(&self.mangled_name(), "free_iter");
+            let out_ty = convert_ret_ty(item, &base_ty);
+            let conv = box_ptr_conv(item, quote!{item});
+            // The handle lives outside of any impl block, so can't use `Self`.
+            let item_ty = match base_ty {
+                Some(ref base) => substitute(item.into_token_stream(), &[(syn::Ident::new("Self", Span::call_site()), base.clone())]),
+                None => item.into_token_stream(),
+            };
+            out = parse_quote!(-> *mut #handle);
+            iter_externs = quote!{
+                #[allow(non_camel_case_types)]
+                pub struct #handle(Box<dyn Iterator<Item = #item_ty>>);
+
+                #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+                #[no_mangle]
+                pub extern "C" fn #next_name(it: *mut #handle, swig_out: *mut #out_ty) -> bool {
+                    ffi_ref!(@mut it);
+                    match it.0.next() {
+                        Some(item) => {
+                            unsafe {
+                                assert!(!swig_out.is_null());
+                                *swig_out = box_ptr!(#conv);
+                            }
+                            true
+                        },
+                        None => false,
+                    }
+                }
+
+                #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+                #[no_mangle]
+                pub extern "C" fn #free_name(it: *mut #handle) {
+                    ffi_free!(@box it);
+                }
+            };
+        }
+
+        // Tuple returns are split up into out-parameters `swig_out_N`, each
+        // of which is converted in the same way as a single return.
+        let mut tuple_outs = Vec::<TokenStream>::new();
+        if let Some(elems) = fn_tuple_ret(&self.fn_def.decl.output) {
+            elems.iter().enumerate().for_each(|(i, ty)| {
+                let out_name = syn::Ident::new(&format!("swig_out_{}", i), Span::call_site());
+                let out_ty = convert_ret_ty(ty, &base_ty);
+                args.push(quote!{#out_name: *mut #out_ty});
+
+                let idx = syn::Index::from(i);
+                let conv = box_ptr_conv(ty, quote!{res.#idx});
+                tuple_outs.push(quote!{
+                    unsafe {
+                        assert!(!#out_name.is_null());
+                        *#out_name = box_ptr!(#conv);
+                    }
+                });
+            });
+        }
+        // Similar to the above, this later calls the appropriate macro function
+        // as to whether we need to do some pointer/box stuff. Tuple results
+        // are all passed back through the out-parameters.
+        let ret = if !tuple_outs.is_empty() {
+            TokenStream::new()
+        } else {
+            let res_ref = if iter_ret.is_some() {
+                let handle = self.iter_handle();
+                quote!{#handle(Box::new(res))}
+            } else if let syn::ReturnType::Type(_, ref ty) = self.fn_def.decl.output {
+                match str_ret_kind(ty) {
+                    Some(StrRet::Borrowed) | Some(StrRet::Static) => {
+                        // Borrowed strings aren't NUL-terminated, so the length
+                        // is passed back through an extra out-parameter.
+                        args.push(quote!{swig_out_len: *mut usize});
+                        quote!{@borrowed_str res, swig_out_len}
+                    },
+                    _ => match bytes_kind(ty) {
+                        Some(bk) => {
+                            args.push(quote!{swig_out_len: *mut usize});
+                            match bk {
+                                BytesKind::Owned => quote!{@bytes res, swig_out_len},
+                                BytesKind::Like => quote!{@bytes_like res, swig_out_len},
+                                _ => quote!{@borrowed_bytes res, swig_out_len},
+                            }
+                        },
+                        // Views keep `self` borrowed for as long as they live.
+                        None if borrowed_class(ty).is_some() && self.has_receiver() => match **ty {
+                            syn::Type::Reference(ref r) if r.mutability.is_some() => quote!{@borrowed_mut res, wrapped_self},
+                            _ => quote!{@borrowed res, wrapped_self},
+                        },
+                        None => box_ptr_conv(ty, quote!{res}),
+                    },
+                }
+            } else {
+                quote!{@prim res}
+            };
+            quote!{box_ptr!(#res_ref)}
+        };
+
+        if opts.awaitable {
+            let result_args = args.split_off(inputs_len);
+            return self.awaitable_extern(&ext_name, &args, &caller, &caller_ref, &callbacks, &iter_externs
+                , &result_args, &out, &tuple_outs, &ret);
+        }
+        // Otherwise the future of an `async fn` is run to completion here.
+        let call = if self.asyncness {
+            quote!{swig_block_on(#callee(#(#caller),*))}
+        } else {
+            quote!{#callee(#(#caller),*)}
+        };
+
+        // Generate the function. We also inject some macro
+        // definitions to help with converting pointers into types and types
+        // into pointers.
+        let tokens = quote! {
+            #(#callbacks)*
+
+            #iter_externs
+
+            #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+            #[no_mangle]
+            pub extern "C" fn #ext_name(#(#args),*)  #out {
+                #gil_check
+                #(ffi_ref!(#caller_ref);)*
+                let res = #call;
+                #(#tuple_outs)*
+                #ret
+            }
+        };
+        tokens
+    }
+}
+
+impl<'a> InternalFn<'a> {
+    /// The externs of an awaitable function. The first spawns the future on
+    /// the executor thread, returning a task handle straight away, and the
+    /// others ask to be notified once it completes (or block until then),
+    /// take its result, and free the task.
+    #[allow(clippy::too_many_arguments)]
+    fn awaitable_extern(&self, ext_name: &syn::Ident, args: &[TokenStream], caller: &[syn::Ident], caller_ref: &[TokenStream]
+            , callbacks: &[TokenStream], iter_externs: &TokenStream, result_args: &[TokenStream], out: &syn::ReturnType
+            , tuple_outs: &[TokenStream], ret: &TokenStream) -> TokenStream {
+        let name = &self.fn_def.ident;
+        let shared_self = self.fn_def.decl.inputs.iter().all(|arg| match arg {
+            syn::FnArg::SelfRef(sr) => sr.mutability.is_none(),
+            syn::FnArg::SelfValue(_) => false,
+            _ => true,
+        });
+        if !shared_self {
+            panic!("Awaitable method `{}` can only take `&self`", name);
+        }
+        if let syn::ReturnType::Type(_, ref ty) = self.fn_def.decl.output {
+            let borrowed = str_ret_kind(ty) == Some(StrRet::Borrowed) || borrowed_class(ty).is_some() 
+                || matches!(bytes_kind(ty), Some(BytesKind::Borrowed) | Some(BytesKind::BorrowedMut));
+            if borrowed {
+                panic!("Awaitable function `{}` cannot return a reference", name);
+            }
+        }
+
+        // The extern returns before the future runs, so the future owns
+        // copies of any strings and byte buffers. Objects are kept alive by
+        // the target language until it completes.
+        let mut owned = Vec::<TokenStream>::new();
+        let mut call_args = Vec::<TokenStream>::new();
+        for (id, conv) in caller.iter().zip(caller_ref) {
+            let kind = conv.clone().into_iter().nth(1).map_or(String::new(), |tt| tt.to_string());
+            match kind.as_str() {
+                "str" | "bytes" => {
+                    let to_owned = if kind == "str" { quote!{to_owned} } else { quote!{to_vec} };
+                    owned.push(quote!{let #id = #id.#to_owned();});
+                    call_args.push(quote!{&#id});
+                },
+                "opt_str" => {
+                    owned.push(quote!{let #id = #id.map(str::to_owned);});
+                    call_args.push(quote!{#id.as_ref().map(String::as_str)});
+                },
+                "ref" if *id == "wrapped_self" => call_args.push(quote!{#id}),
+                "ref" | "mut" | "bytes_mut" | "ref_fn" | "mut_fn" | "dyn_ref" | "dyn_mut" => {
+                    panic!("Awaitable function `{}` cannot borrow `{}`, as it returns before the future runs", name, id);
+                },
+                _ => call_args.push(quote!{#id}),
+            }
+        }
+        // `self` stays borrowed until the future completes.
+        let (hold, held) = if self.has_receiver() {
+            (quote!{let swig_held = ffi_borrow!(@held wrapped_self);}, quote!{let _swig_held = swig_held;})
+        } else {
+            (quote!{}, quote!{})
+        };
+
+        // The async keywords are spanned like the user's function, for the
+        // edition of their crate.
+        let async_ = syn::Ident::new("async", name.span());
+        let await_ = syn::Ident::new("await", name.span());
+        let callee = self.callee();
+        let mangled = self.mangled_name();
+        let task = self.task_handle();
+        let done = self.callback_struct(&syn::Ident::new("done", Span::call_site()));
+        let notify_name = swig_fn(&mangled, "notify");
+        let wait_name = swig_fn(&mangled, "wait");
+        let result_name = swig_fn(&mangled, "result");
+        let free_name = swig_fn(&mangled, "free_task");
+        // The handle lives outside of any impl block, so can't use `Self`.
+        let ret_ty = match self.fn_def.decl.output {
+            syn::ReturnType::Type(_, ref ty) => match self.base_ty() {
+                Some(base) => substitute(ty.into_token_stream(), &[(syn::Ident::new("Self", Span::call_site()), base)]),
+                None => ty.into_token_stream(),
+            },
+            syn::ReturnType::Default => quote!{()},
+        };
+        quote! {
+            #(#callbacks)*
+
+            #iter_externs
+
+            #[allow(non_camel_case_types)]
+            pub struct #task(::std::sync::Arc<SwigTask<#ret_ty>>);
+
+            #[repr(C)]
+            #[allow(non_camel_case_types)]
+            pub struct #done {
+                pub user_data: *mut ::std::os::raw::c_void,
+                pub call: extern "C" fn(*mut ::std::os::raw::c_void),
+                pub drop: extern "C" fn(*mut ::std::os::raw::c_void),
+            }
+
+            #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+            #[no_mangle]
+            pub extern "C" fn #ext_name(#(#args),*) -> *mut #task {
+                #(ffi_ref!(#caller_ref);)*
+                #hold
+                #(#owned)*
+                let future = #async_ move {
+                    #held
+                    #callee(#(#call_args),*).#await_
+                };
+                box_ptr!(#task(ffi_spawn!(future)))
+            }
+
+            #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+            #[no_mangle]
+            pub extern "C" fn #notify_name(task: *mut #task, done: #done) {
+                ffi_ref!(@ref task);
+                ffi_ref!(@box_fn done () -> ());
+                task.0.notify(done);
+            }
+
+            #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+            #[no_mangle]
+            pub extern "C" fn #wait_name(task: *mut #task) {
+                ffi_ref!(@ref task);
+                task.0.wait();
+            }
+
+            #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+            #[no_mangle]
+            pub extern "C" fn #result_name(task: *mut #task, #(#result_args),*) #out {
+                ffi_ref!(@ref task);
+                let res = match task.0.take() {
+                    Some(res) => res,
+                    None => return SwigErrorValue::swig_error_value(),
+                };
+                #(#tuple_outs)*
+                #ret
+            }
+
+            #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+            #[no_mangle]
+            pub extern "C" fn #free_name(task: *mut #task) {
+                ffi_free!(@box task);
+            }
+        }
+    }
+}
+
+
+/// Helper function to define the exported/mangled names.
+fn swig_fn(name: &syn::Ident, fn_name: &str) -> syn::Ident {
+    syn::Ident::new(&format!("{}{}_{}", SwigTag::SwigInject, fn_name, name), Span::call_site())
+}
+
+fn swig_free(name: &syn::Ident) -> syn::Ident {
+    swig_fn(name, "free")
+}
+
+impl ToSwig for syn::DeriveInput {
+    fn to_swig(&self) -> String {
+        // Generate the SWIG wrapper code as a string.
+        // Basically, a class for the Rust struct `Foo` is just a wrapper
+        // class called `Foo` which contains a pointer to the actual Rust
+        // object.
 
         // prefix with tag
         let mut swigged = SwigTag::CodeStart.to_string();
         let mut swigged_h = SwigTag::HdrStart.to_string();
 
-        let name = &self.ident;
+        // Types are renamed for the target language only, so the generated
+        // C++ can keep using the Rust name.
+        let opts = SwigOptions::from_attrs(&self.attrs);
+        if opts.skip || opts.constructor || !opts.defaults.is_empty() {
+            panic!("Only `name` can be given in `#[swig(..)]` on a type");
+        }
+        let instances = derive_instances(self);
+        let mut pyi = String::new();
+        if opts.name.is_some() && instances.len() > 1 {
+            panic!("Cannot rename `{}`, since it is exported as several classes", self.ident);
+        }
+
         match &self.data {
             syn::Data::Struct(ref _ds) => {
-                // simple wrapper definition to wrap opaque pointer.
-                // methods get added elsewhere
-                swigged.push_str(&format!("\
+                for (ty, name) in instances {
+                    if let Some(ref rename) = opts.name {
+                        swigged_h.push_str(&format!("%rename({}) {};\n", rename, name));
+                    }
+                    if let Some(docs) = struct_docs(self) {
+                        swigged_h.push_str(&swig_docs(&name, &docs, true));
+                    }
+                    // simple wrapper definition to wrap opaque pointer.
+                    // methods get added elsewhere
+                    swigged.push_str(&format!("\
 // Wrapper for Rust class {name}
 class {name} {{
     public:
-        ffi::{name} *self;
-        {name}(ffi::{name} *ptr) {{
+        ffi::{ffi_name} *self;
+        swig_handle swig_kind;
+        {name}(ffi::{ffi_name} *ptr, swig_handle kind = SWIG_BOX) {{
             self = ptr;
+            swig_kind = kind;
         }};
         ~{name}(){{
-            ffi::{free_name}(self);
+            switch (swig_kind) {{
+                case SWIG_ARC: ffi::{free_arc}(self); break;
+                case SWIG_RC: ffi::{free_rc}(self); break;
+                case SWIG_BORROWED:
+                case SWIG_BORROWED_CONST: ffi::{free_borrowed}(self); break;
+                default: ffi::{free_name}(self);
+            }}
             self = NULL;
         }};
-    ", name=name, free_name=swig_free(&name))
-                );
+    ", name=name, ffi_name=ffi_class_name(&ty), free_name=swig_free(&syn::Ident::new(&name, Span::call_site()))
+        , free_arc=swig_fn(&syn::Ident::new(&name, Span::call_site()), "free_arc")
+        , free_rc=swig_fn(&syn::Ident::new(&name, Span::call_site()), "free_rc")
+        , free_borrowed=swig_fn(&syn::Ident::new(&name, Span::call_site()), "free_borrowed"))
+                    );
 
 swigged_h.push_str(&format!("\
 // Wrapper for Rust class {name}
 class {name} {{
-    ffi::{name} *self;
+    ffi::{ffi_name} *self;
     public:
         ~{name}();
-    ", name=name)
-                );
-                // pull out any derive implementations we want to wrap
-                // TODO: do this in a less ad-hoc way
-                get_derives(&self.attrs).iter().for_each(|w| {
-                    match w.as_str() {
-                        "Default" => {
-                            swigged.push_str(&format!(
-                                "{name}() {{ self = {def_name}(); }};\n",
-                                name=name, def_name=swig_fn(&name, "default")
-                            ));
-                            swigged_h.push_str(&format!("{}();\n",name));
-                        },
-                        _ => (),
+    ", name=name, ffi_name=ffi_class_name(&ty))
+                    );
+                    // pull out any derive implementations we want to wrap
+                    // TODO: do this in a less ad-hoc way
+                    let class = syn::Ident::new(&name, Span::call_site());
+                    let mut extends = String::new();
+                    get_derives(&self.attrs).iter().for_each(|w| {
+                        match w.as_str() {
+                            "Default" => {
+                                swigged.push_str(&format!(
+                                    "{name}() {{ self = {def_name}(); swig_kind = SWIG_BOX; }};\n",
+                                    name=name, def_name=swig_fn(&class, "default")
+                                ));
+                                swigged_h.push_str(&format!("{}();\n",name));
+                            },
+                            "Clone" => {
+                                swigged_h.push_str(&format!("{name}(const {name} &other);\n", name=name));
+                                // Copies share a reference counted object,
+                                // only a deep copy clones it.
+                                extends.push_str(&format!("
+    %newobject __copy__;
+    {name} *__copy__() {{
+        return new {name}(*$self);
+    }}
+#ifdef SWIGPYTHON
+    %newobject __deepcopy__;
+    {name} *__deepcopy__(PyObject *memo) {{
+        return new {name}(ffi::{clone_name}($self->self));
+    }}
+#endif", name=name, clone_name=swig_fn(&class, "clone")));
+                            },
+                            "Debug" => {
+                                extends.push_str(&format!("
+    std::string __repr__() {{
+        return swig_take_string(ffi::{debug_name}($self->self));
+    }}", debug_name=swig_fn(&class, "debug")));
+                            },
+                            "Display" => {
+                                extends.push_str(&format!("
+    std::string __str__() {{
+        return swig_take_string(ffi::{display_name}($self->self));
+    }}", display_name=swig_fn(&class, "display")));
+                            },
+                            "PartialEq" => {
+                                extends.push_str(&format!("
+    bool __eq__(const {name} *other) {{
+        return other && ffi::{eq_name}($self->self, other->self);
+    }}
+    bool __ne__(const {name} *other) {{
+        return !other || !ffi::{eq_name}($self->self, other->self);
+    }}", name=name, eq_name=swig_fn(&class, "eq")));
+                            },
+                            "Hash" => {
+                                extends.push_str(&format!("
+    uint64_t __hash__() {{
+        return ffi::{hash_name}($self->self);
+    }}", hash_name=swig_fn(&class, "hash")));
+                            },
+                            "PartialOrd" => {
+                                let cmp_name = swig_fn(&class, "cmp");
+                                for (op, cond) in &[("<", "c == -1"), ("<=", "c == -1 || c == 0"), (">", "c == 1"), (">=", "c == 1 || c == 0")] {
+                                    extends.push_str(&format!("
+    bool operator{op}(const {name} *other) {{
+        if (!other) return false;
+        int8_t c = ffi::{cmp_name}($self->self, other->self);
+        return {cond};
+    }}", op=op, name=name, cmp_name=cmp_name, cond=cond));
+                                }
+                            },
+                            _ => (),
+                        }
+
+                    });
+                    // A copy of a shared handle is another strong reference,
+                    // and otherwise owns a clone. Without `Clone` the wrapper
+                    // can't be copied, as it would free the object twice.
+                    if get_derives(&self.attrs).iter().any(|w| w == "Clone") {
+                        swigged.push_str(&format!("    {name}(const {name} &other) {{
+        swig_kind = other.swig_kind;
+        switch (swig_kind) {{
+            case SWIG_ARC: self = ffi::{share_arc}(other.self); break;
+            case SWIG_RC: self = ffi::{share_rc}(other.self); break;
+            default: self = ffi::{clone}(other.self); swig_kind = SWIG_BOX;
+        }}
+    }};
+", name=name, share_arc=swig_fn(&class, "share_arc"), share_rc=swig_fn(&class, "share_rc")
+    , clone=swig_fn(&class, "clone")));
+                    } else {
+                        swigged.push_str(&format!("    {name}(const {name} &other) = delete;
+", name=name));
+                    }
+                    swigged.push_str(&format!("    {name} &operator=(const {name} &other) = delete;
+", name=name));
+                    swigged.push_str("};\n");
+                    swigged_h.push_str("};\n");
+                    if !extends.is_empty() {
+                        swigged_h.push_str(&format!("%extend {name} {{{extends}\n}};\n", name=name, extends=extends));
                     }
 
-                });
-                swigged.push_str("};\n");
-                swigged_h.push_str("};\n");
+                    // Type stubs for Python.
+                    pyi.push_str(&pyi_class(&name, opts.name.as_ref().unwrap_or(&name)));
+                    for w in get_derives(&self.attrs) {
+                        let stubs: &[&str] = match w.as_str() {
+                            "Default" => &["def __init__(self) -> None: ..."],
+                            "Clone" => &["def __copy__(self) -> $Self$: ...", "def __deepcopy__(self, memo: Any) -> $Self$: ..."],
+                            "Debug" => &["def __repr__(self) -> str: ..."],
+                            "Display" => &["def __str__(self) -> str: ..."],
+                            "PartialEq" => &["def __eq__(self, other: object) -> bool: ...", "def __ne__(self, other: object) -> bool: ..."],
+                            "Hash" => &["def __hash__(self) -> int: ..."],
+                            "PartialOrd" => &[
+                                "def __lt__(self, other: $Self$) -> bool: ...", "def __le__(self, other: $Self$) -> bool: ...",
+                                "def __gt__(self, other: $Self$) -> bool: ...", "def __ge__(self, other: $Self$) -> bool: ...",
+                            ],
+                            _ => &[],
+                        };
+                        for stub in stubs {
+                            pyi.push_str(&pyi_stub(Some(&name), &stub.replace("$Self$", &format!("${}$", name))));
+                        }
+                    }
+                }
             },
             _ => unimplemented!(),
         }
-        swigged.push_str(&SwigTag::CodeEnd.to_str());
-        swigged_h.push_str(&SwigTag::HdrEnd.to_str());
+        swigged.push_str(SwigTag::CodeEnd.to_str());
+        swigged_h.push_str(SwigTag::HdrEnd.to_str());
         swigged.push_str(&swigged_h);
+        swigged.push_str(&pyi);
         swigged
     }
 }
@@ -379,111 +1837,1152 @@ impl<'a> ToSwig for InternalFn<'a> {
         let mut swigged_h = SwigTag::HdrStart.to_string();
 
         let name = &self.fn_def.ident;
-        let cb_fn = cbindgen::ir::Function::load(name.to_string(), 
-                                                    &self.fn_def.decl,
-                                                    true,
-                                                    &[],
-                                                    &None).unwrap();
+        let opts = SwigOptions::from_attrs(&self.fn_def.attrs);
+        let is_ctor = name == "new" || opts.constructor;
 
         let mut args = String::new();
         let mut caller = String::new();
+        // Keyword argument names, to tell constructors apart in Python.
+        let mut arg_names = Vec::<String>::new();
+        // Optional primitives need typemaps to accept `None` in Python.
+        let mut optionals = Vec::<String>::new();
+        let optional_from = self.optional_from(&opts);
+        // Boxed trait objects are owned by Rust once passed in.
+        let mut disowned = Vec::<String>::new();
+        // Objects borrowed by the call, and whether mutably.
+        let mut borrowed_args = Vec::<(String, bool)>::new();
+        // Python callables passed as closures need some glue code, which is
+        // only available for Python.
+        let mut callback_glue = String::new();
 
         // Convert function arguments
-        cb_fn.args.iter().for_each(|arg| {
-            if args.len() > 0 {
+        self.fn_def.decl.inputs.iter().for_each(|arg| {
+            if !args.is_empty() {
                 args += ", ";
             }
-            if caller.len() > 0 {
+            if !caller.is_empty() {
                 caller += ", ";
             }
-            if arg.0 == "self" {
-                caller += "$self->self";
-            } else {
-                args += &cbindgen_write(arg);
-                caller += &arg.0;
+            match arg {
+                syn::FnArg::SelfRef(_) | syn::FnArg::SelfValue(_) => {
+                    caller += "$self->self";
+                },
+                syn::FnArg::Captured(ref ac) => {
+                    let id = arg_ident(&self.fn_def.ident, ac).to_string();
+                    arg_names.push(id.clone());
+                    if let Some((_, inner)) = shared_inner(&ac.ty) {
+                        panic!("Cannot take `{}` as an argument, try `&{}` instead"
+                            , ac.ty.clone().into_token_stream(), inner.clone().into_token_stream());
+                    }
+                    // Trailing parameters with a default value, or which are
+                    // `Option`s, can be left out.
+                    let default = opts.default_of(&id).map(|d| format!(" = {}", d))
+                        .or_else(|| if arg_names.len() > optional_from { Some(" = NULL".to_string()) } else { None })
+                        .unwrap_or_default();
+                    if let Some(inner) = option_inner(&ac.ty) {
+                        if str_ret_kind(inner).is_some() {
+                            args += &format!("const char *{}{}", id, default);
+                        } else {
+                            let c_ty = cbindgen_write(&cbindgen_type(inner).expect("Cannot take `Option<()>` as an argument"));
+                            args += &format!("const {} *{}{}", c_ty, id, default);
+                            optionals.push(format!("const {} *{}", c_ty, id));
+                        }
+                        caller += &id;
+                        return;
+                    }
+                    if let Some(cb) = callback_sig(&ac.ty) {
+                        let cb_name = self.callback_struct(&syn::Ident::new(&id, Span::call_site()));
+                        args += &format!("PyObject *{}", id);
+                        caller += &format!("{}_wrap({})", cb_name, id);
+                        callback_glue += &py_callback_glue(&cb_name.to_string(), &cb);
+                        return;
+                    }
+                    if let Some((kind, name)) = dyn_trait(&ac.ty) {
+                        args += &format!("{} *{}", name, id);
+                        caller += &format!("{}_vtable({})", name, id);
+                        if kind == DynKind::Boxed {
+                            disowned.push(format!("{} *{}", name, id));
+                        }
+                        return;
+                    }
+                    if let Some(inner) = borrowed_class(&ac.ty) {
+                        let constness = if is_mut_ref(&ac.ty) { "" } else { "const " };
+                        args += &format!("{}{} *{}", constness, self.swig_class(inner), id);
+                        caller += &format!("{}->self", id);
+                        borrowed_args.push((id, is_mut_ref(&ac.ty)));
+                        return;
+                    }
+                    // Byte buffers get special typemaps which match on
+                    // the `swig_byte *, size_t` pair.
+                    match bytes_kind(&ac.ty) {
+                        Some(BytesKind::Like) | None => {
+                            let ty = cbindgen_type(&ac.ty).expect("Cannot take `()` as an argument");
+                            args += &cbindgen_write(&(id.clone(), ty));
+                            args += &default;
+                            caller += &id;
+                        },
+                        Some(bk) => {
+                            let constness = if bk == BytesKind::BorrowedMut { "" } else { "const " };
+                            args += &format!("{c}swig_byte *{id}, size_t {id}_len", c=constness, id=id);
+                            caller += &format!("{id}, {id}_len", id=id);
+                        },
+                    }
+                },
+                _ => (),
             }
         });
 
 
         // Convert return type
-        let mut out = cbindgen_write(&cb_fn.ret);
-        if out == "Self" {
-            out = self.base.clone().expect("Cannot convert `Self` return type without provided base name.
-                            Try: `#[swiggen(Foo)]` in macro").to_string();
-        } else if out == "String" {
-            out = "char *".to_string()
+        let tuple_ret = fn_tuple_ret(&self.fn_def.decl.output);
+        let iter_ret = fn_iter_ret(&self.fn_def.decl.output);
+        let mut out = match self.fn_def.decl.output {
+            // Tuples and iterators are returned as a generated class, named below.
+            syn::ReturnType::Type(..) if tuple_ret.is_some() || iter_ret.is_some() => String::new(),
+            syn::ReturnType::Type(_, ref ty) => {
+                cbindgen_type(ty).map_or("void".to_string(), |t| cbindgen_write(&t))
+            },
+            syn::ReturnType::Default => "void".to_string(),
+        };
+        let str_ret = fn_str_ret(&self.fn_def.decl.output);
+        let bytes_ret = fn_bytes_ret(&self.fn_def.decl.output);
+        // `Arc`/`Rc` returns are a new wrapper holding a strong reference.
+        let shared_ret = match self.fn_def.decl.output {
+            syn::ReturnType::Type(_, ref ty) => shared_inner(ty),
+            syn::ReturnType::Default => None,
+        };
+        // References are a wrapper which doesn't own the object, and keeps
+        // the object it borrows from alive.
+        let borrowed_ret = match self.fn_def.decl.output {
+            syn::ReturnType::Type(_, ref ty) => borrowed_class(ty),
+            syn::ReturnType::Default => None,
+        };
+        if let Some(sr) = str_ret {
+            out = match sr {
+                StrRet::Owned => "char *",
+                StrRet::Static | StrRet::Borrowed => "std::string",
+            }.to_string();
+        } else if bytes_ret.is_some() {
+            out = "swig_bytes".to_string();
+        } else if let Some((_, inner)) = shared_ret {
+            out = format!("{} *", self.swig_class(inner));
+        } else if let Some(inner) = borrowed_ret {
+            out = format!("{} *", self.swig_class(inner));
+        } else if let syn::ReturnType::Type(_, ref ty) = self.fn_def.decl.output {
+            // Opaque types are returned as their wrapper class.
+            if tuple_ret.is_none() && iter_ret.is_none() && needs_ref(ty) {
+                out = self.swig_class(ty);
+            }
         }
+        // Other than from constructors, they are handed out as a new wrapper
+        // owning the object, as wrappers can't be copied without `Clone`.
+        let class_ret = !is_ctor && match self.fn_def.decl.output {
+            syn::ReturnType::Type(_, ref ty) => out == self.swig_class(ty),
+            syn::ReturnType::Default => false,
+        };
+        if class_ret {
+            out = format!("{} *", out);
+        }
+
+        // Tuples get a result struct, named after the function.
+        let res_name = match self.base_class() {
+            Some(base) => format!("{}_{}_result", base, self.swig_name()),
+            None => format!("{}_result", self.swig_name()),
+        };
+        if tuple_ret.is_some() {
+            out = format!("{} *", res_name);
+        }
+        let iter_name = format!("{}_iter", self.mangled_name());
+        if iter_ret.is_some() {
+            out = format!("{} *", iter_name);
+        }
+        // Associated functions without a receiver become static methods, and
+        // those returning the base type are named factories.
+        let has_receiver = self.has_receiver();
+        if opts.constructor && (self.base.is_none() || has_receiver) {
+            panic!("`#[swig(constructor)]` needs an associated function without `self`, in `#[swiggen(Foo)]`");
+        }
+        let is_static = self.base.is_some() && !has_receiver && !is_ctor;
         let mut ret_out = out.clone();
 
 
         // Convert function name.
-        let name = if name.to_string() == "new" {
-            // Custom format for new functions
+        let name = if is_ctor {
+            // Custom format for constructors
             ret_out = "".to_string();
             out = "new PKG_NAME::".to_string() + &out;
-            self.base.clone().expect("Cannot convert `Self` return type without provided base name.
-                            Try: `#[swiggen(Foo)]` in macro").to_string()
+            self.base_class().expect("Cannot convert `Self` return type without provided base name.
+                            Try: `#[swiggen(Foo)]` in macro")
         } else {
-            name.to_string()
+            self.swig_name()
         };
         
         // Get the mangled name exported by Rust
-        let ext_name = swig_fn(&self.fn_def.ident, "ffi");
+        let ext_name = swig_fn(&self.mangled_name(), "ffi");
+
+        // Awaitable functions return a task instead, and its `result` method
+        // returns what the function would.
+        if opts.awaitable && is_ctor {
+            panic!("Constructor `{}` cannot be awaitable", self.fn_def.ident);
+        }
+        let scope = self.base_class().map_or(String::new(), |b| format!("{}::", b));
+        let task_name = format!("{}_task", self.mangled_name());
+        let ret_target = if opts.awaitable {
+            format!("{}::result", task_name)
+        } else {
+            format!("{}{}", scope, name)
+        };
+
+        // Struct definition and SWIG declarations needed for tuple returns.
+        let mut tuple_h = String::new();
+        let tuple_elems: Vec<SwigTupleElem> = tuple_ret.iter().flat_map(|elems| {
+            elems.iter().enumerate().map(|(i, ty)| swig_tuple_elem(self, ty, i))
+        }).collect();
+        if !tuple_elems.is_empty() {
+            let fields: Vec<String> = tuple_elems.iter().enumerate()
+                .map(|(i, e)| format!("{} _{};", e.field_ty, i)).collect();
+            let deletes: Vec<String> = tuple_elems.iter().enumerate()
+                .filter(|(_, e)| e.is_class)
+                .map(|(i, _)| format!("delete _{};", i)).collect();
+            swigged.push_str(&format!("
+                struct {res} {{
+                    {fields}
+                    ~{res}() {{
+                        {deletes}
+                    }}
+                }};\n"
+                , res=res_name, fields=fields.join("\n                    "), deletes=deletes.join("\n                        ")));
+
+            // In Python, the struct is unpacked into a native tuple. Wrapper
+            // classes are handed over to the tuple so they outlive the struct.
+            let items: Vec<String> = (0..tuple_elems.len()).map(|i| format!("swig_res._{}", i)).collect();
+            let mut py = format!("swig_res = val\n                    val = ({}{})", items.join(", "), if items.len() == 1 { "," } else { "" });
+            tuple_elems.iter().enumerate().filter(|(_, e)| e.is_class).for_each(|(i, _)| {
+                py += &format!("\n                    val[{i}].thisown = True\n                    swig_res._{i} = None", i=i);
+            });
+            // In Ruby, the struct is converted into an `Array` instead, taking
+            // ownership of any wrapper classes.
+            let rb: Vec<String> = tuple_elems.iter().enumerate().map(|(i, e)| {
+                let field = format!("$1->_{}", i);
+                let item = ruby_value(&e.field_ty, &field);
+                if e.is_class {
+                    format!("rb_ary_push($result, {});\n                    {} = NULL;", item, field)
+                } else {
+                    format!("rb_ary_push($result, {});", item)
+                }
+            }).collect();
+            tuple_h.push_str(&format!("
+                %nodefaultctor {res};
+                struct {res} {{
+                    {fields}
+                    ~{res}();
+                }};
+                %newobject {target};
+                %pythonappend {target} %{{
+                    {py}
+                %}}
+                #ifdef SWIGRUBY
+                %typemap(out) {res} * {{
+                    $result = rb_ary_new();
+                    {rb}
+                    delete $1;
+                }}
+                #endif\n"
+                , res=res_name, fields=fields.join("\n                    "), target=ret_target, py=py
+                , rb=rb.join("\n                    ")));
+        }
+
+        // Iterators are wrapped in a class which fetches one item ahead, and
+        // implements the iteration protocol of each target language.
+        let mut iter_h = String::new();
+        if let Some(ref item) = iter_ret {
+            let elem = swig_tuple_elem(self, item, 0);
+            let mangled = self.mangled_name();
+            swigged.push_str(&format!("
+                // Iterator returned by {scope}{name}
+                class {cls} {{
+                    public:
+                        typedef {field_ty} item_type;
+                        {cls}(ffi::{handle} *ptr) : self(ptr), swig_fetched(false), swig_done(false), swig_item() {{}}
+                        ~{cls}() {{
+                            {delete_item}
+                            ffi::{free}(self);
+                        }}
+                        bool has_next() {{
+                            if (!swig_fetched) {{
+                                {ffi_ty} swig_out_0;
+                                swig_done = !ffi::{next}(self, &swig_out_0);
+                                if (!swig_done) swig_item = {conv};
+                                swig_fetched = true;
+                            }}
+                            return !swig_done;
+                        }}
+                        item_type next_item() {{
+                            if (!has_next()) return item_type();
+                            swig_fetched = false;
+                            return swig_item;
+                        }}
+                    private:
+                        {cls}(const {cls} &);
+                        ffi::{handle} *self;
+                        bool swig_fetched;
+                        bool swig_done;
+                        item_type swig_item;
+                }};\n"
+                , scope=scope, name=name, cls=iter_name, field_ty=elem.field_ty, ffi_ty=elem.ffi_ty, conv=elem.conv
+                , handle=self.iter_handle(), next=swig_fn(&mangled, "next"), free=swig_fn(&mangled, "free_iter")
+                , delete_item=if elem.is_class { "if (swig_fetched && !swig_done) delete swig_item;" } else { "" }));
+            let class_item = if elem.is_class { format!("\n                %newobject {}::next_item;", iter_name) } else { String::new() };
+            iter_h.push_str(&format!("
+                %newobject {target};{class_item}
+                %nodefaultctor {cls};
+                class {cls} {{
+                    public:
+                        ~{cls}();
+                        bool has_next();
+                        {field_ty} next_item();
+                }};
+                #ifdef SWIGPYTHON
+                %extend {cls} {{
+                %pythoncode %{{
+                    def __iter__(self):
+                        return self
+
+                    def __next__(self):
+                        if not self.has_next():
+                            raise StopIteration
+                        return self.next_item()
+
+                    next = __next__
+                %}}
+                }};
+                #endif
+                #ifdef SWIGRUBY
+                %mixin {cls} \"Enumerable\";
+                %feature(\"action\") {cls}::each {{
+                    while (arg1->has_next()) {{
+                        rb_yield(rb_funcall(self, rb_intern(\"next_item\"), 0));
+                    }}
+                }}
+                %extend {cls} {{
+                    void each() {{}}
+                }};
+                #endif
+                #ifdef SWIGJAVA
+                %typemap(javainterfaces) {cls} \"java.util.Iterator<$typemap(jboxtype, {field_ty})>, java.lang.Iterable<$typemap(jboxtype, {field_ty})>\";
+                %typemap(javacode) {cls} %{{
+                  public boolean hasNext() {{
+                    return has_next();
+                  }}
+
+                  public $typemap(jboxtype, {field_ty}) next() {{
+                    if (!has_next()) {{
+                      throw new java.util.NoSuchElementException();
+                    }}
+                    return next_item();
+                  }}
+
+                  public java.util.Iterator<$typemap(jboxtype, {field_ty})> iterator() {{
+                    return this;
+                  }}
+                %}}
+                #endif\n"
+                , target=ret_target, cls=iter_name, field_ty=elem.field_ty, class_item=class_item));
+        }
+
+        // The body converting what the extern `ext_name` returns, when called
+        // with `caller`.
+        let (mut body, result_body) = {
+            let ret_body = |ext_name: &str, caller: &str| -> String {
+                // Pointer + length returns pass the length back as a final argument.
+                let len_caller = if caller.is_empty() {
+                    "&swig_len".to_string()
+                } else {
+                    caller.to_string() + ", &swig_len"
+                };
+                if str_ret == Some(StrRet::Borrowed) || str_ret == Some(StrRet::Static) {
+                    // Copy the borrowed string out before anything can invalidate it.
+                    format!("\
+                                uintptr_t swig_len = 0;
+                                const char *swig_ptr = ffi::{ext_name}({caller});
+                                return swig_ptr ? std::string(swig_ptr, swig_len) : std::string();"
+                        , ext_name=ext_name, caller=len_caller)
+                } else if !tuple_elems.is_empty() {
+                    // Collect the out-parameters into the result struct.
+                    let decls: Vec<String> = tuple_elems.iter().enumerate()
+                        .map(|(i, e)| format!("{} swig_out_{};", e.ffi_ty, i)).collect();
+                    let outs: Vec<String> = (0..tuple_elems.len()).map(|i| format!("&swig_out_{}", i)).collect();
+                    let convs: Vec<String> = tuple_elems.iter().map(|e| e.conv.clone()).collect();
+                    let caller = if caller.is_empty() {
+                        outs.join(", ")
+                    } else {
+                        format!("{}, {}", caller, outs.join(", "))
+                    };
+                    format!("\
+                                {decls}
+                                ffi::{ext_name}({caller});
+                                return new {res}{{ {convs} }};"
+                        , decls=decls.join("\n                        "), ext_name=ext_name, caller=caller
+                        , res=res_name, convs=convs.join(", "))
+                } else if iter_ret.is_some() {
+                    format!("return new {cls}(ffi::{ext_name}({caller}));"
+                        , cls=iter_name, ext_name=ext_name, caller=caller)
+                } else if let Some(inner) = borrowed_ret {
+                    let kind = match self.fn_def.decl.output {
+                        syn::ReturnType::Type(_, ref ty) if is_mut_ref(ty) => "SWIG_BORROWED",
+                        _ => "SWIG_BORROWED_CONST",
+                    };
+                    format!("return new {cls}(ffi::{ext_name}({caller}), {kind});"
+                        , cls=self.swig_class(inner), ext_name=ext_name, caller=caller, kind=kind)
+                } else if let Some((kind, inner)) = shared_ret {
+                    let cls = if is_ctor { format!("PKG_NAME::{}", self.swig_class(inner)) } else { self.swig_class(inner) };
+                    format!("return new {cls}(ffi::{ext_name}({caller}), {kind});"
+                        , cls=cls, ext_name=ext_name, caller=caller, kind=kind.swig_handle())
+                } else if class_ret {
+                    format!("return new {cls}(ffi::{ext_name}({caller}));"
+                        , cls=out.trim_end_matches(" *"), ext_name=ext_name, caller=caller)
+                } else if let Some(bk) = bytes_ret {
+                    // Copy the buffer out, and release it if it was owned.
+                    let (ptr_ty, free) = match bk {
+                        BytesKind::Owned | BytesKind::Like => ("uint8_t *", "\n                        ffi::free_bytes(swig_ptr, swig_len);"),
+                        _ => ("const uint8_t *", ""),
+                    };
+                    format!("\
+                                uintptr_t swig_len;
+                                {ptr_ty}swig_ptr = ffi::{ext_name}({caller});
+                                swig_bytes swig_res((const char *) swig_ptr, swig_len);{free}
+                                return swig_res;"
+                        , ptr_ty=ptr_ty, ext_name=ext_name, caller=len_caller, free=free)
+                } else {
+                    format!("return ({out})(ffi::{ext_name}({caller}));"
+                        , ext_name=ext_name, out=out, caller=caller)
+                }
+            };
+            let result_body = if opts.awaitable {
+                Some(ret_body(&swig_fn(&self.mangled_name(), "result").to_string(), "self"))
+            } else {
+                None
+            };
+            (ret_body(&ext_name.to_string(), &caller), result_body)
+        };
+
+        // The task of an awaitable function is wrapped in a class, which is
+        // turned into an `asyncio` future in Python. It is notified from the
+        // executor thread once the future completes. Other targets have no
+        // event loop to notify, so block on the task instead.
+        let mut task_class = String::new();
+        let mut task_h = String::new();
+        let mut blocking = None;
+        if let Some(result_body) = result_body {
+            let mangled = self.mangled_name();
+            let done = self.callback_struct(&syn::Ident::new("done", Span::call_site())).to_string();
+            let done_glue = py_callback_glue(&done, &Callback { kind: Some(DynKind::Boxed), inputs: vec![], output: None });
+            task_class = format!("
+#ifdef SWIGPYTHON
+{done_glue}
+#endif
+                // Result of {scope}{name}, once its future completes
+                class {cls} {{
+                    public:
+                        {cls}(ffi::{handle} *ptr) : self(ptr) {{}}
+                        ~{cls}() {{
+                            ffi::{free}(self);
+                        }}
+#ifdef SWIGPYTHON
+                        void on_done(PyObject *callable) {{
+                            ffi::{notify}(self, {done}_wrap(callable));
+                        }}
+#endif
+                        void wait() {{
+                            ffi::{wait}(self);
+                        }}
+                        {ret_out} result() {{
+                            {body}
+                        }}
+                    private:
+                        {cls}(const {cls} &);
+                        ffi::{handle} *self;
+                }};\n"
+                , scope=scope, name=name, cls=task_name, handle=self.task_handle(), free=swig_fn(&mangled, "free_task")
+                , notify=swig_fn(&mangled, "notify"), wait=swig_fn(&mangled, "wait"), done=done, done_glue=done_glue
+                , ret_out=ret_out, body=result_body);
+            let result_new = if shared_ret.is_some() || class_ret {
+                format!("\n                %newobject {}::result;", task_name)
+            } else {
+                String::new()
+            };
+            task_h = format!("
+#ifdef SWIGPYTHON
+                %newobject {scope}{name};{result_new}
+                %nodefaultctor {cls};
+                class {cls} {{
+                    public:
+                        ~{cls}();
+                        void on_done(PyObject *callable);
+                        {ret_out} result();
+                }};
+                %pythonprepend {scope}{name} %{{
+                    _swig_loop = _swig_running_loop()
+                %}}
+                %pythonappend {scope}{name} %{{
+                    val = _swig_awaitable(val, {owner}, _swig_loop)
+                %}}
+#endif\n"
+                , scope=scope, name=name, result_new=result_new, cls=task_name, ret_out=ret_out, owner=if has_receiver { "self" } else { "None" });
+            blocking = Some((ret_out.clone(), out.clone(), format!("\
+                        {cls} swig_task(ffi::{ext_name}({caller}));
+                        swig_task.wait();
+                        return swig_task.result();"
+                , cls=task_name, ext_name=ext_name, caller=caller)));
+            body = format!("return new {cls}(ffi::{ext_name}({caller}));"
+                , cls=task_name, ext_name=ext_name, caller=caller);
+            ret_out = format!("{} *", task_name);
+            out = ret_out.clone();
+        }
+
+        // Objects shared with Rust, or borrowed through `&`, can be aliased, so
+        // they can't be mutated or moved out of.
+        if self.mutates_receiver() {
+            body = format!("\
+                        if ($self->swig_kind != SWIG_BOX && $self->swig_kind != SWIG_BORROWED) {{
+                            ffi::swig_raise_error(\"`{name}` needs `&mut self`, but this `{cls}` is shared with Rust or borrowed through `&`\");
+                            return{ret};
+                        }}
+                        {body}"
+                , name=name, cls=self.base_class().unwrap(), body=body
+                , ret=if ret_out == "void" { "" } else { " {}" });
+        }
+        // Objects taken by reference can't be `None`, nor be mutated through
+        // a shared wrapper.
+        for (id, mutable) in borrowed_args.iter().rev() {
+            if *mutable {
+                body = format!("\
+                        if ({id}->swig_kind != SWIG_BOX && {id}->swig_kind != SWIG_BORROWED) {{
+                            ffi::swig_raise_error(\"`{name}` needs `{id}` by `&mut`, but it is shared with Rust or borrowed through `&`\");
+                            return{ret};
+                        }}
+                        {body}"
+                    , id=id, name=name, body=body, ret=if ret_out == "void" { "" } else { " {}" });
+            }
+            body = format!("\
+                        if (!{id}) {{
+                            ffi::swig_raise_error(\"`{name}` needs a `{id}`, not `None`\");
+                            return{ret};
+                        }}
+                        {body}"
+                , id=id, name=name, body=body, ret=if ret_out == "void" { "" } else { " {}" });
+        }
 
         // The following code generates the function definitions and the header
         // Code needed for SWIG to generate bindings.
 
+        swigged.push_str(&callback_glue);
+        swigged.push_str(&task_class);
         if self.base.is_none() {
-            swigged.push_str(&format!("\
+            let definition = |ret_out: &str, body: &str| format!("\
                 {ret_out} {name}({args}) {{
-                    return ({out})(ffi::{ext_name}({caller}));
+                    {body}
                 }}"
-                , name=name, ext_name=ext_name, out=out, ret_out=ret_out, args=args, caller=caller));
+                , name=name, ret_out=ret_out, args=args, body=body);
+            swigged.push_str(&python_or(definition(&ret_out, &body)
+                , blocking.as_ref().map(|(ret_out, _, body)| definition(ret_out, body))));
+        }
+        if let Some(docs) = get_docs(&self.fn_def.attrs) {
+            let target = match self.base_class() {
+                Some(base) => format!("{}::{}", base, name),
+                None => name.clone(),
+            };
+            swigged_h.push_str(&swig_docs(&target, &docs, false));
+        }
+        swigged_h.push_str(&tuple_h);
+        swigged_h.push_str(&iter_h);
+        swigged_h.push_str(&task_h);
+        if !disowned.is_empty() {
+            swigged_h.push_str(&format!("
+                %apply SWIGTYPE *DISOWN {{ {} }};\n", disowned.join(", ")));
+        }
+        for opt in optionals.iter() {
+            let ty = &opt[..opt.rfind('*').unwrap() + 1];
+            swigged_h.push_str(&format!("
+#ifdef SWIGPYTHON
+                %apply {ty}OPTIONAL {{ {opt} }};
+#endif\n", ty=ty, opt=opt));
+        }
+        if (shared_ret.is_some() || borrowed_ret.is_some() || class_ret) && !is_ctor && !opts.awaitable {
+            swigged_h.push_str(&format!("
+                %newobject {}{};", scope, name));
         }
-        if let Some(base) = self.base {
-            // Note the %extend is used by SWIG to make this a class method for
-            // `base`.
+        if borrowed_ret.is_some() {
+            if !has_receiver {
+                panic!("`{}` returns a reference, which is only supported for methods borrowing from `self`", name);
+            }
+            // Only Python can hold on to the parent from the view. Elsewhere
+            // the parent has to outlive its views.
             swigged_h.push_str(&format!("
+#ifdef SWIGPYTHON
+                %pythonappend {}::{} %{{
+                    val._swig_owner = self
+                %}}
+#endif\n", self.base_class().unwrap(), name));
+        }
+        // Other Python threads can run while in Rust, as the GIL is
+        // released around the call. Only on request, as they can then call
+        // into the same objects at the same time.
+        if opts.release_gil {
+            let mut targets = vec![match self.base_class() {
+                Some(base) => format!("{}::{}", base, name),
+                None => name.clone(),
+            }];
+            if is_ctor {
+                targets.push(format!("{}::_swig_ctor_{}", name, self.mangled_name()));
+            }
+            for target in targets {
+                swigged_h.push_str(&format!("
+                %feature(\"nothreadallow\", \"0\") {};\n", target));
+            }
+        }
+        // Note the %extend is used by SWIG to make a method a class method for
+        // its base.
+        let declaration = |ret_out: &str, out: &str, body: &str| match self.base_class() {
+            Some(base) => format!("
                 %extend {base_name} {{
-                    {ret_out} {name}({args}) {{
-                        return ({out})(ffi::{ext_name}({caller}));
+                    {static_}{ret_out} {name}({args}) {{
+                        {body}
                     }}
                 }};\n"
-                ,name=name, ext_name=ext_name, base_name=base, ret_out=ret_out, out=out, args=args, caller=caller));
-        } else {
-            swigged_h.push_str(&format!("\
+                ,name=name, base_name=base, ret_out=ret_out, args=args, body=body
+                , static_=if is_static { "static " } else { "" }),
+            None => format!("\
                 {out} {name}({args});"
-                , name=name, out=out, args=args));
+                , name=name, out=out, args=args),
+        };
+        let newobject = if shared_ret.is_some() || class_ret {
+            format!("
+                %newobject {}{};", scope, name)
+        } else {
+            String::new()
+        };
+        swigged_h.push_str(&python_or(declaration(&ret_out, &out, &body)
+            , blocking.as_ref().map(|(ret_out, out, body)| newobject.clone() + &declaration(ret_out, out, body))));
+        if let Some(base) = self.base_class() {
+            if is_ctor {
+                // Also register the constructor by its argument names, so
+                // those SWIG can't tell apart can be called with keywords.
+                let names: String = arg_names.iter().map(|n| format!("\"{}\", ", n)).collect();
+                swigged_h.push_str(&format!("
+#ifdef SWIGPYTHON
+                %newobject {base_name}::_swig_ctor_{mangled};
+                %extend {base_name} {{
+                    static {base_name} *_swig_ctor_{mangled}({args}) {{
+                        {body}
+                    }}
+                }};
+                %pythoncode %{{
+                    _swig_add_ctor({py_class}({base_name}), ({names}), \"_swig_ctor_{mangled}\")
+                %}}
+#endif\n"
+                    , base_name=base, mangled=self.mangled_name(), args=args, body=body, names=names
+                    , py_class=SwigTag::PyClass));
+            }
+        }
+        if !disowned.is_empty() {
+            swigged_h.push_str(&format!("
+                %clear {};\n", disowned.join(", ")));
+        }
+        if !optionals.is_empty() {
+            swigged_h.push_str(&format!("
+#ifdef SWIGPYTHON
+                %clear {};
+#endif\n", optionals.join(", ")));
+        }
+        if !callback_glue.is_empty() {
+            swigged.insert_str(SwigTag::CodeStart.len(), "\n#ifdef SWIGPYTHON\n");
+            swigged.push_str("\n#endif\n");
+            swigged_h.insert_str(SwigTag::HdrStart.len(), "\n#ifdef SWIGPYTHON\n");
+            // Other targets have no glue to call back into, so skip the
+            // function there, but say so rather than dropping it silently.
+            swigged_h.push_str(&format!("
+#else
+#warning \"`{}` takes a closure and is only exported to Python\"
+#endif\n", self.swig_name()));
         }
 
-        swigged.push_str(&SwigTag::CodeEnd.to_str());
-        swigged_h.push_str(&SwigTag::HdrEnd.to_str());
+        // Type stub for Python.
+        let class_of = |ty: &syn::Type| self.swig_class(ty);
+        let mut py_params = Vec::<String>::new();
+        if has_receiver || is_ctor {
+            py_params.push("self".to_string());
+        }
+        let captured = self.fn_def.decl.inputs.iter().filter_map(|arg| match arg {
+            syn::FnArg::Captured(ac) => Some(ac),
+            _ => None,
+        });
+        for (i, ac) in captured.enumerate() {
+            let id = arg_ident(&self.fn_def.ident, ac).to_string();
+            let default = if i >= optional_from { " = ..." } else { "" };
+            py_params.push(format!("{}: {}{}", id, py_type(&ac.ty, &class_of), default));
+        }
+        let (py_name, mut py_ret) = if is_ctor {
+            ("__init__".to_string(), "None".to_string())
+        } else {
+            (name.clone(), match self.fn_def.decl.output {
+                syn::ReturnType::Type(_, ref ty) => py_type(ty, &class_of),
+                syn::ReturnType::Default => "None".to_string(),
+            })
+        };
+        if opts.awaitable {
+            py_ret = format!("Awaitable[{}]", py_ret);
+        }
+        let stub = format!("{}def {}({}) -> {}: ..."
+            , if is_static { "@staticmethod\n" } else { "" }, py_name, py_params.join(", "), py_ret);
+
+        swigged.push_str(SwigTag::CodeEnd.to_str());
+        swigged_h.push_str(SwigTag::HdrEnd.to_str());
         swigged.push_str(&swigged_h);
+        swigged.push_str(&pyi_stub(self.base_class().as_deref(), &stub));
         swigged
     }
 }
 
 
+/// Use `python` for Python, and `other` (if given) for other targets.
+fn python_or(python: String, other: Option<String>) -> String {
+    match other {
+        Some(other) => format!("
+#ifdef SWIGPYTHON
+{}
+#else
+{}
+#endif\n", python, other),
+        None => python,
+    }
+}
+
+/// Generate the C++ glue to pass a Python callable to Rust as the closure
+/// struct `cb_name`. The callable is kept alive until Rust drops the closure.
+fn py_callback_glue(cb_name: &str, cb: &Callback) -> String {
+    let mut params = vec!["void *user_data".to_string()];
+    let mut fmt = String::new();
+    let mut builds = String::new();
+    for (i, ty) in cb.inputs.iter().enumerate() {
+        let arg = format!("a{}", i);
+        let c_ty = cbindgen_type(ty).expect("Cannot take `()` as a callback argument");
+        params.push(cbindgen_write(&(arg.clone(), c_ty)));
+        let (f, build, _) = py_conv(ty, &arg, "");
+        fmt += f;
+        builds += &format!(", {}", build);
+    }
+    let (out, res_decl, res_conv, res_ret) = match cb.output {
+        Some(ref ty) => {
+            let out = cbindgen_write(&cbindgen_type(ty).expect("unit return"));
+            let (_, _, conv) = py_conv(ty, "", "swig_res");
+            (out.clone(), format!("{} res = {}();", out, out), format!("res = ({}) {};", out, conv), "\n    return res;".to_string())
+        },
+        None => ("void".to_string(), String::new(), String::new(), String::new()),
+    };
+    format!("
+static {out} {cb}_call({params}) {{
+    PyGILState_STATE swig_gil = PyGILState_Ensure();
+    PyObject *swig_res = PyObject_CallFunction((PyObject *) user_data, (char *) \"({fmt})\"{builds});
+    {res_decl}
+    if (!swig_res) {{
+        swig_py_callback_error();
+    }} else {{
+        {res_conv}
+        if (PyErr_Occurred()) swig_py_callback_error();
+        Py_DECREF(swig_res);
+    }}
+    PyGILState_Release(swig_gil);{res_ret}
+}}
+
+// The GIL may have been released for the call.
+static ffi::{cb} {cb}_wrap(PyObject *callable) {{
+    PyGILState_STATE swig_gil = PyGILState_Ensure();
+    Py_INCREF(callable);
+    PyGILState_Release(swig_gil);
+    ffi::{cb} cb;
+    cb.user_data = callable;
+    cb.call = {cb}_call;
+    cb.drop = swig_py_callback_drop;
+    return cb;
+}}
+", out=out, cb=cb_name, params=params.join(", "), fmt=fmt, builds=builds
+    , res_decl=res_decl, res_conv=res_conv, res_ret=res_ret)
+}
+
 /// Generate extern and SWIG code for a `#[derive(Swig)]` annotated item.
 pub fn impl_extern_it(ast: &syn::DeriveInput) -> TokenStream {
     let comment = ast.to_swig();
-    let comment = format!("#[doc=\"{}\"] #[allow(non_camel_case_types)] struct {}{};", comment, SwigTag::SwigInject, ast.ident);
-    let doc_comment: syn::ItemStruct = syn::parse_str(&comment).expect("failed to generate SWIG code correctly");
-    let mut tokens: TokenStream = doc_comment.into_token_stream();
-    tokens.append_all(ast.as_extern().into_iter());
+    let hidden = syn::Ident::new(&format!("{}{}", SwigTag::SwigInject, ast.ident), Span::call_site());
+    let mut tokens = quote!{
+        #[doc=#comment]
+        #[allow(non_camel_case_types)]
+        struct #hidden;
+    };
+    tokens.append_all(ast.as_extern());
     tokens
 }
 
-/// Generate extern and SWIG code for a `#[swiggen]` annotated method.
-pub fn impl_extern_fn(base_name: &Option<syn::Ident>, ast: &syn::ItemFn) -> TokenStream {
-    let ifn = InternalFn {
-        base: base_name,
-        fn_def: ast,
+/// Generate the vtable, adapter and SWIG director class for a `#[swiggen]`
+/// annotated trait.
+/// Only required methods go through the vtable, any provided methods keep
+/// their Rust implementation.
+pub fn impl_extern_trait(ast: &syn::ItemTrait) -> TokenStream {
+    let name = &ast.ident;
+    let vtable = trait_vtable(name);
+    let adapter = trait_adapter(name);
+
+    let methods: Vec<&syn::TraitItemMethod> = ast.items.iter().filter_map(|item| match item {
+        syn::TraitItem::Method(tim) if tim.default.is_none() => Some(tim),
+        _ => None,
+    }).collect();
+
+    let mut fields = Vec::<TokenStream>::new();
+    let mut impls = Vec::<TokenStream>::new();
+    let mut virtuals = Vec::<String>::new();
+    let mut trampolines = String::new();
+    let mut inits = String::new();
+    let mut docs = get_docs(&ast.attrs).map_or(String::new(), |d| swig_docs(&name.to_string(), &d, true));
+    let class_of = |ty: &syn::Type| swig_class_name(ty);
+    let mut pyi = pyi_class(&name.to_string(), &name.to_string());
+    for m in &methods {
+        let meth = &m.sig.ident;
+        if let Some(d) = get_docs(&m.attrs) {
+            docs.push_str(&swig_docs(&format!("{}::{}", name, meth), &d, false));
+        }
+        if m.sig.decl.generics.type_params().next().is_some() {
+            panic!("Cannot implement generic method `{}::{}` from SWIG", name, meth);
+        }
+        let mut inputs = m.sig.decl.inputs.iter();
+        let receiver = match inputs.next() {
+            Some(arg @ syn::FnArg::SelfRef(_)) => arg,
+            _ => panic!("Trait method `{}::{}` must take `&self` or `&mut self`", name, meth),
+        };
+        let (ids, tys): (Vec<syn::Ident>, Vec<syn::Type>) = inputs.map(|arg| match arg {
+            syn::FnArg::Captured(syn::ArgCaptured { pat: syn::Pat::Ident(pi), ty, .. }) => (pi.ident.clone(), ty.clone()),
+            _ => panic!("Unsupported argument in trait method `{}::{}`", name, meth),
+        }).unzip();
+        let output = &m.sig.decl.output;
+        // The vtable is called across FFI without any conversion, so only
+        // types with the same layout on both sides can be used.
+        let ret_ty = match output {
+            syn::ReturnType::Type(_, ref ty) => Some(&**ty),
+            syn::ReturnType::Default => None,
+        };
+        for ty in tys.iter().chain(ret_ty) {
+            match cbindgen_type(ty) {
+                Some(ty::Type::Primitive(_)) => {},
+                None if Some(ty) == ret_ty => {},
+                _ => panic!("Unsupported type `{}` in trait method `{}::{}`, only primitive types can be used",
+                            ty.clone().into_token_stream(), name, meth),
+            }
+        }
+        let py_params: String = ids.iter().zip(tys.iter())
+            .map(|(id, ty)| format!(", {}: {}", id, py_type(ty, &class_of))).collect();
+        let py_ret = match output {
+            syn::ReturnType::Type(_, ty) => py_type(ty, &class_of),
+            syn::ReturnType::Default => "None".to_string(),
+        };
+        pyi.push_str(&pyi_stub(Some(&name.to_string()), &format!("def {}(self{}) -> {}: ...", meth, py_params, py_ret)));
+        let (arg_ids, arg_tys, caller_ids) = (&ids, &tys, &ids);
+        fields.push(quote!{
+            pub #meth: extern "C" fn(*mut ::std::os::raw::c_void, #(#arg_tys),*) #output
+        });
+        impls.push(quote!{
+            fn #meth(#receiver, #(#arg_ids: #arg_tys),*) #output {
+                (self.0.#meth)(self.0.user_data, #(#caller_ids),*)
+            }
+        });
+
+        // C++ side: a pure virtual method to be overridden by the director,
+        // and a trampoline to call it from Rust.
+        let args: Vec<String> = ids.iter().zip(tys.iter()).map(|(id, ty)| {
+            let ty = cbindgen_type(ty).expect("Cannot take `()` as an argument");
+            cbindgen_write(&(id.to_string(), ty))
+        }).collect();
+        let callers: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        let out = match output {
+            syn::ReturnType::Type(_, ref ty) => cbindgen_type(ty).map_or("void".to_string(), |t| cbindgen_write(&t)),
+            syn::ReturnType::Default => "void".to_string(),
+        };
+        virtuals.push(format!("virtual {out} {meth}({args}) = 0;", out=out, meth=meth, args=args.join(", ")));
+        let mut tramp_args = vec!["void *user_data".to_string()];
+        tramp_args.extend(args);
+        // Exceptions can't unwind through Rust, so they are recorded and
+        // raised again once the outer extern call returns. In Python that is
+        // the exception raised by the override, which is still pending.
+        trampolines.push_str(&format!("
+static {out} {name}_{meth}_trampoline({args}) {{
+    try {{
+        return (({name} *) user_data)->{meth}({callers});
+    }} catch (...) {{
+#ifdef SWIGPYTHON
+        PyGILState_STATE swig_gil = PyGILState_Ensure();
+        if (PyErr_Occurred()) {{
+            swig_py_callback_error();
+        }} else {{
+            ffi::swig_raise_error(\"exception raised in {name}.{meth}\");
+        }}
+        PyGILState_Release(swig_gil);
+#else
+        ffi::swig_raise_error(\"exception raised in {name}.{meth}\");
+#endif
+        return {out}();
+    }}
+}}
+", out=out, name=name, meth=meth, args=tramp_args.join(", "), callers=callers.join(", ")));
+        inits.push_str(&format!("
+    vt.{meth} = {name}_{meth}_trampoline;", meth=meth, name=name));
+    }
+
+    let swigged = format!("\
+{code}
+// Director base class for Rust trait {name}
+class {name} {{
+    public:
+        virtual ~{name}() {{}}
+        {virtuals}
+}};
+{trampolines}
+static void {name}_drop_trampoline(void *user_data) {{
+    delete ({name} *) user_data;
+}}
+
+// Wrap an implementation of {name} in a vtable to pass to Rust.
+static ffi::{vtable} {name}_vtable({name} *obj) {{
+    ffi::{vtable} vt;
+    vt.user_data = obj;{inits}
+    vt.drop = {name}_drop_trampoline;
+    return vt;
+}}
+{code_end}{hdr}{docs}
+%feature(\"director\") {name};
+class {name} {{
+    public:
+        virtual ~{name}();
+        {virtuals}
+}};
+{hdr_end}{pyi}"
+        , code=SwigTag::CodeStart, code_end=SwigTag::CodeEnd, hdr=SwigTag::HdrStart, hdr_end=SwigTag::HdrEnd
+        , name=name, vtable=vtable, virtuals=virtuals.join("\n        ")
+        , trampolines=trampolines, inits=inits, docs=docs, pyi=pyi);
+    let hidden = swig_fn(name, "trait");
+
+    quote!{
+        #[allow(non_camel_case_types)]
+        #[doc=#swigged]
+        struct #hidden;
+
+        /// C vtable for implementing the trait in the target language.
+        #[repr(C)]
+        pub struct #vtable {
+            pub user_data: *mut ::std::os::raw::c_void,
+            #(#fields,)*
+            pub drop: extern "C" fn(*mut ::std::os::raw::c_void),
+        }
+
+        /// Implements the trait by calling through the vtable.
+        pub struct #adapter(pub #vtable);
+
+        impl #name for #adapter {
+            #(#impls)*
+        }
+
+        impl Drop for #adapter {
+            fn drop(&mut self) {
+                (self.0.drop)(self.0.user_data)
+            }
+        }
+    }
+}
+
+/// The operator traits which can be exported with `#[swiggen]`, with the
+/// C++ operator (or method) each is mapped to in SWIG, and the resulting
+/// Python method.
+const SWIG_OPS: &[(&str, &str, &str)] = &[
+    ("Add", "operator+", "__add__"),
+    ("Sub", "operator-", "__sub__"),
+    ("Mul", "operator*", "__mul__"),
+    ("Div", "operator/", "__truediv__"),
+    ("Rem", "operator%", "__mod__"),
+    ("Neg", "operator-", "__neg__"),
+    ("Index", "__getitem__", "__getitem__"),
+];
+
+/// Generate extern and SWIG code for a `#[swiggen]` annotated impl of one of
+/// the operator traits in `SWIG_OPS`.
+/// Operands are taken by reference and cloned, so the types involved need to
+/// implement `Clone`.
+pub fn impl_extern_op(ast: &syn::ItemImpl) -> TokenStream {
+    let self_ty = &*ast.self_ty;
+    let trait_path = match ast.trait_ {
+        Some((_, ref path, _)) => path,
+        None => panic!("`#[swiggen]` on an impl block needs an operator trait, try `swiggen_hack!` for methods"),
+    };
+    let seg = trait_path.segments.iter().last().expect("empty trait path");
+    let trait_name = seg.ident.to_string();
+    let (op, py_op) = SWIG_OPS.iter().find(|(t, _, _)| *t == trait_name).map(|(_, op, py_op)| (*op, *py_op))
+        .unwrap_or_else(|| panic!("Unsupported trait `{}` in `#[swiggen]` impl, expected one of: {}. Other traits are exported with `#[swiggen(Foo)]`"
+            , trait_name, SWIG_OPS.iter().map(|(t, _, _)| *t).collect::<Vec<_>>().join(", ")));
+
+    // The right hand side (or index) type defaults to `Self`.
+    let rhs: Option<syn::Type> = match seg.arguments {
+        syn::PathArguments::AngleBracketed(ref ab) => ab.args.iter().filter_map(|a| match a {
+            syn::GenericArgument::Type(ty) => Some(ty.clone()),
+            _ => None,
+        }).next(),
+        _ => None,
+    };
+    let unary = trait_name == "Neg";
+    let rhs_ty: Option<syn::Type> = if unary { None } else { Some(rhs.clone().unwrap_or_else(|| self_ty.clone())) };
+    let output: syn::Type = ast.items.iter().filter_map(|item| match item {
+        syn::ImplItem::Type(it) if it.ident == "Output" => Some(it.ty.clone()),
+        _ => None,
+    }).next().expect("Operator impl is missing `type Output`");
+    let output: syn::Type = syn::parse2(substitute(output.into_token_stream()
+        , &[(syn::Ident::new("Self", Span::call_site()), self_ty.clone())]))
+        .expect("failed to substitute `Self`");
+
+    let class = swig_class_name(self_ty);
+    let method = syn::Ident::new(&trait_name.to_lowercase(), Span::call_site());
+    let mut op_name = format!("op_{}", method);
+    if let Some(ref rhs) = rhs {
+        op_name += &format!("_{}", type_suffix(rhs));
+    }
+    let op_ident = syn::Ident::new(&format!("{}_{}", op_name, class), Span::call_site());
+    let ext_name = swig_fn(&op_ident, "ffi");
+
+    // Extern definition
+    let base_ty = Some(self_ty.clone());
+    let out_ty = convert_ret_ty(&output, &base_ty);
+    let mut args = vec![quote!{arg: *const #self_ty}];
+    let mut refs = vec![quote!{@ref arg}];
+    let mut call_args = vec![];
+    if let Some(ref rhs_ty) = rhs_ty {
+        if needs_ref(rhs_ty) {
+            args.push(quote!{other: *const #rhs_ty});
+            refs.push(quote!{@ref other});
+            call_args.push(quote!{other.clone()});
+        } else {
+            args.push(quote!{other: #rhs_ty});
+            refs.push(quote!{@prim other});
+            call_args.push(quote!{other});
+        }
+    }
+    let call = if trait_name == "Index" {
+        quote!{<#self_ty as #trait_path>::index(arg, #(#call_args),*).clone()}
+    } else {
+        quote!{<#self_ty as #trait_path>::#method(arg.clone(), #(#call_args),*)}
     };
+    let conv = box_ptr_conv(&output, quote!{res});
+
+    // SWIG wrapper, as a method on the wrapper class.
+    let mut params = String::new();
+    let mut callers = "$self->self".to_string();
+    if let Some(ref rhs_ty) = rhs_ty {
+        if needs_ref(rhs_ty) {
+            params = format!("const {} *other", swig_class_name(rhs_ty));
+            callers += ", other->self";
+        } else {
+            let c_ty = cbindgen_type(rhs_ty).expect("Cannot use `()` as an operand");
+            params = cbindgen_write(&("other".to_string(), c_ty));
+            callers += ", other";
+        }
+    }
+    let (out, body, newobject) = if needs_ref(&output) {
+        let out_class = swig_class_name(&output);
+        (format!("{} *", out_class), format!("return new {}(ffi::{}({}));", out_class, ext_name, callers)
+            , format!("%newobject {}::{};\n", class, op))
+    } else {
+        let c_ty = cbindgen_write(&cbindgen_type(&output).expect("Cannot return `()` from an operator"));
+        (c_ty.clone(), format!("return ({})(ffi::{}({}));", c_ty, ext_name, callers), String::new())
+    };
+    let class_of = |ty: &syn::Type| swig_class_name(ty);
+    let py_params = rhs_ty.as_ref().map_or(String::new(), |rhs| format!(", other: {}", py_type(rhs, &class_of)));
+    let stub = format!("def {}(self{}) -> {}: ...", py_op, py_params, py_type(&output, &class_of));
+    let swigged = format!("{code}{code_end}{hdr}
+{newobject}%extend {class} {{
+    {out} {op}({params}) {{
+        {body}
+    }}
+}};
+{hdr_end}{pyi}"
+        , code=SwigTag::CodeStart, code_end=SwigTag::CodeEnd, hdr=SwigTag::HdrStart, hdr_end=SwigTag::HdrEnd
+        , newobject=newobject, class=class, out=out, op=op, params=params, body=body
+        , pyi=pyi_stub(Some(&class), &stub));
+    let hidden = swig_fn(&op_ident, "hidden_ffi");
+
+    quote!{
+        #[allow(non_camel_case_types)]
+        #[doc=#swigged]
+        struct #hidden;
+
+        #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+        #[no_mangle]
+        pub extern "C" fn #ext_name(#(#args),*) -> #out_ty {
+            #(ffi_ref!(#refs);)*
+            let res = #call;
+            box_ptr!(#conv)
+        }
+    }
+}
+
+/// Generate extern and SWIG code for a `#[swiggen]` annotated method.
+/// Generic methods are exported once for each of the `instantiate(..)`
+/// instances in `args`.
+pub fn impl_extern_fn(args: &Args, ast: &syn::ItemFn, asyncness: bool) -> TokenStream {
+    impl_extern_method(args, None, &[], ast, asyncness)
+}
+
+/// Generate extern and SWIG code for a method of an impl block, which may be
+/// the impl of `trait_`. `base_params` are the type parameters of the base
+/// type, in the order it declares them.
+fn impl_extern_method(args: &Args, trait_: Option<&syn::Path>, base_params: &[syn::Ident], ast: &syn::ItemFn, asyncness: bool) -> TokenStream {
+    if args.instances.is_empty() {
+        if ast.decl.generics.type_params().next().is_some() {
+            panic!("Cannot export generic function `{}` without concrete types.
+                    Try: `#[swiggen(instantiate(T = u32))]` in macro", ast.ident);
+        }
+        return extern_fn_tokens(&InternalFn {
+            base: &args.base,
+            trait_,
+            fn_def: ast,
+            instance: None,
+            asyncness,
+        });
+    }
 
+    let mut tokens = TokenStream::new();
+    for inst in &args.instances {
+        let inst = &inst.ordered(base_params);
+        let generic = InternalFn {
+            base: &args.base,
+            trait_,
+            fn_def: ast,
+            instance: Some(inst),
+            asyncness,
+        };
+        let self_ty = if generic.base_generics().is_empty() {
+            None
+        } else {
+            generic.base_ty()
+        };
+        let fn_def = monomorphize(ast, inst, self_ty);
+        tokens.append_all(extern_fn_tokens(&InternalFn {
+            base: &args.base,
+            trait_,
+            fn_def: &fn_def,
+            instance: Some(inst),
+            asyncness,
+        }));
+    }
+    tokens
+}
+
+fn extern_fn_tokens(ifn: &InternalFn) -> TokenStream {
     let tok = ifn.as_extern();
     let comment = ifn.to_swig();
-    let hidden = swig_fn(&ast.ident, "hidden_ffi");
+    let hidden = swig_fn(&ifn.mangled_name(), "hidden_ffi");
     quote! {
         #[allow(non_snake_case)]
         #[doc=#comment]
@@ -493,20 +2992,291 @@ pub fn impl_extern_fn(base_name: &Option<syn::Ident>, ast: &syn::ItemFn) -> Toke
     }
 }
 
+/// Generate a getter and SWIG constant for a `#[swiggen]` annotated `const`
+/// or `static` item. Associated consts give the type they belong to as
+/// `base` (and the trait, if in a trait impl), and are exported as
+/// `Foo_NAME`, which is also made a class attribute in Python.
+pub fn impl_extern_const(base: Option<&syn::Ident>, trait_: Option<&syn::Path>, ident: &syn::Ident, ty: &syn::Type, attrs: &[syn::Attribute]) -> TokenStream {
+    let opts = SwigOptions::from_attrs(attrs);
+    if opts.skip {
+        return TokenStream::new();
+    }
+    let name = opts.name.unwrap_or_else(|| ident.to_string());
+    let swig_name = base.map_or(name.clone(), |b| format!("{}_{}", b, name));
+    let mangled = syn::Ident::new(&swig_name, Span::call_site());
+    let ext_name = swig_fn(&mangled, "const");
+    let value = match (base, trait_) {
+        (Some(b), Some(t)) => quote!{ <#b as #t>::#ident },
+        (Some(b), None) => quote!{ <#b>::#ident },
+        (None, _) => quote!{ #ident },
+    };
+
+    // String constants are always `'static`, so are handed out as pointer +
+    // length without copying, and copied once by the SWIG wrapper.
+    let is_str = match ty {
+        syn::Type::Reference(r) => type_name(&r.elem) == "str",
+        _ => false,
+    };
+    let (out_ty, conv, c_ty, py_ty) = if is_str {
+        (quote!{ *const c_char }, quote!{ @borrowed_str #value, swig_out_len }, "const char *".to_string(), "str".to_string())
+    } else {
+        match cbindgen_type(ty) {
+            Some(c_ty @ ty::Type::Primitive(_)) => {
+                let class_of = |ty: &syn::Type| swig_class_name(ty);
+                (ty.into_token_stream(), quote!{ @prim #value }, cbindgen_write(&c_ty), py_type(ty, &class_of))
+            },
+            _ => panic!("Only constants of primitive or `&str` type can be exported, not `{}`", ident),
+        }
+    };
+
+    let py_attr = base.map_or(String::new(), |b| format!("
+#ifdef SWIGPYTHON
+%extend {class} {{
+%pythoncode %{{
+    {name} = {module}.{swig_name}
+%}}
+}};
+#endif", class=b, name=name, module=SwigTag::PyModule, swig_name=swig_name));
+    let class = base.map(|b| b.to_string());
+    let getter = if is_str {
+        format!("    static const std::string {swig_name}_str = []() {{
+        uintptr_t swig_len;
+        const char *swig_ptr = ffi::{ext_name}(&swig_len);
+        return std::string(swig_ptr, swig_len);
+    }}();
+    static const char * const {swig_name} = {swig_name}_str.c_str();"
+            , swig_name=swig_name, ext_name=ext_name)
+    } else {
+        format!("    static {c_ty} const {swig_name} = ffi::{ext_name}();", c_ty=c_ty, swig_name=swig_name, ext_name=ext_name)
+    };
+    let swigged = format!("{code}
+{getter}
+{code_end}{hdr}
+%constant {c_ty} {swig_name} = PKG_NAME::{swig_name};{py_attr}
+{hdr_end}{pyi}"
+        , code=SwigTag::CodeStart, code_end=SwigTag::CodeEnd, hdr=SwigTag::HdrStart, hdr_end=SwigTag::HdrEnd
+        , getter=getter, c_ty=c_ty, swig_name=swig_name, py_attr=py_attr
+        , pyi=pyi_stub(class.as_deref(), &format!("{}: {}", name, py_ty)));
+    let hidden = swig_fn(&mangled, "hidden_const");
+    let len_arg = if is_str { Some(quote!{swig_out_len: *mut usize}) } else { None };
+
+    quote!{
+        #[allow(non_camel_case_types)]
+        #[doc=#swigged]
+        struct #hidden;
+
+        #[allow(non_snake_case, clippy::not_unsafe_ptr_arg_deref)]
+        #[no_mangle]
+        pub extern "C" fn #ext_name(#(#len_arg)*) -> #out_ty {
+            box_ptr!(#conv)
+        }
+    }
+}
+
+/// How the items of nested Rust modules are laid out in Python. In the SWIG
+/// code they are always in nested C++ namespaces.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PyModules {
+    /// Everything is in the top-level module.
+    #[default]
+    Flat,
+    /// Items are in the top-level module, named like `geometry_Point`.
+    Prefix,
+    /// Items are also available from submodules, like `geometry.Point`.
+    Submodules,
+}
+
+
 /// Write the swig code (injected via doc comments) into `swig.i`.
 /// This parses expanded Rust code, and writes the SWIG code to a file.
-pub fn gen_swig(pkg_name: &str, src: &str) {
+/// Items in nested modules are laid out in Python as given by `py_modules`.
+pub fn gen_swig(pkg_name: &str, src: &str, py_modules: PyModules) {
     let mut tmp_file = File::create("swig.i").unwrap();
 
-    tmp_file.write_all(format!("\
-%module {name}
-#define PKG_NAME {name}
-%include <std_vector.i>
-%include <stdint.i>
-%include <std_string.i>
+    tmp_file.write_all(format!("\
+%module(directors=\"1\", threads=\"1\") {name}
+#define PKG_NAME {name}
+%include <std_vector.i>
+%include <stdint.i>
+%include <std_string.i>
+%include <exception.i>
+
+%typemap(newfree) char * \"free_string($1);\";
+// The GIL is only released by functions marked `#[swig(release_gil)]`.
+%feature(\"nothreadallow\");
+%feature(\"kwargs\");
+%feature(\"autodoc\", \"1\");
+%naturalvar std::string;
+
+// Byte buffers are passed in as a `swig_byte *, size_t` pair, and returned
+// as `swig_bytes`.
+%{{
+    #include <cstdint>
+    #include <string>
+
+    typedef uint8_t swig_byte;
+    typedef std::string swig_bytes;
+
+    // How a wrapper class holds its Rust object: boxed and owned by the
+    // wrapper, one strong reference to an `Arc`/`Rc` shared with Rust, or
+    // borrowed from another object (and so never freed), as `&mut` or `&`.
+    enum swig_handle {{ SWIG_BOX, SWIG_ARC, SWIG_RC, SWIG_BORROWED, SWIG_BORROWED_CONST }};
+%}}
+typedef uint8_t swig_byte;
+typedef std::string swig_bytes;
+
+#ifdef SWIGPYTHON
+// Borrow from any object supporting the buffer protocol, without copying.
+%typemap(in) (const swig_byte *, size_t) (Py_buffer view = Py_buffer()) {{
+    if (PyObject_GetBuffer($input, &view, PyBUF_SIMPLE) != 0) SWIG_fail;
+    $1 = (const swig_byte *) view.buf;
+    $2 = (size_t) view.len;
+}}
+%typemap(in) (swig_byte *, size_t) (Py_buffer view = Py_buffer()) {{
+    if (PyObject_GetBuffer($input, &view, PyBUF_WRITABLE) != 0) SWIG_fail;
+    $1 = (swig_byte *) view.buf;
+    $2 = (size_t) view.len;
+}}
+%typemap(freearg) (const swig_byte *, size_t), (swig_byte *, size_t) {{
+    if (view$argnum.obj) PyBuffer_Release(&view$argnum);
+}}
+%typemap(out) swig_bytes {{
+    $result = PyBytes_FromStringAndSize($1.data(), $1.size());
+}}
+#else
+%apply (char *STRING, size_t LENGTH) {{ (const swig_byte *, size_t), (swig_byte *, size_t) }};
+#endif
+
+#ifdef SWIGPYTHON
+// Optional primitives are passed as a pointer, which is null for `None`.
+%define %swig_optional(TYPE, CONV, CHECK)
+%typemap(in) const TYPE *OPTIONAL (TYPE val) {{
+    if ($input != Py_None) {{
+        val = (TYPE) CONV($input);
+        if (PyErr_Occurred()) SWIG_fail;
+        $1 = &val;
+    }}
+}}
+%typemap(typecheck, precedence=SWIG_TYPECHECK_POINTER) const TYPE *OPTIONAL {{
+    $1 = $input == Py_None || CHECK($input);
+}}
+%enddef
+// Narrower integers are range checked, raising an `OverflowError` like
+// SWIG's own typemaps rather than wrapping around.
+%define %swig_optional_int(TYPE, CONV, WIDE, OUT_OF_RANGE)
+%typemap(in) const TYPE *OPTIONAL (TYPE val) {{
+    if ($input != Py_None) {{
+        WIDE wide = CONV($input);
+        if (PyErr_Occurred()) SWIG_fail;
+        if (OUT_OF_RANGE) {{
+            PyErr_SetString(PyExc_OverflowError, \"in method '$symname', argument $argnum out of range of \" #TYPE);
+            SWIG_fail;
+        }}
+        val = (TYPE) wide;
+        $1 = &val;
+    }}
+}}
+%typemap(typecheck, precedence=SWIG_TYPECHECK_POINTER) const TYPE *OPTIONAL {{
+    $1 = $input == Py_None || PyLong_Check($input);
+}}
+%enddef
+%swig_optional(bool, PyObject_IsTrue, PyBool_Check)
+%swig_optional_int(int8_t, PyLong_AsLongLong, long long, wide < INT8_MIN || wide > INT8_MAX)
+%swig_optional_int(int16_t, PyLong_AsLongLong, long long, wide < INT16_MIN || wide > INT16_MAX)
+%swig_optional_int(int32_t, PyLong_AsLongLong, long long, wide < INT32_MIN || wide > INT32_MAX)
+%swig_optional(int64_t, PyLong_AsLongLong, PyLong_Check)
+%swig_optional_int(intptr_t, PyLong_AsLongLong, long long, wide < INTPTR_MIN || wide > INTPTR_MAX)
+%swig_optional_int(uint8_t, PyLong_AsUnsignedLongLong, unsigned long long, wide > UINT8_MAX)
+%swig_optional_int(uint16_t, PyLong_AsUnsignedLongLong, unsigned long long, wide > UINT16_MAX)
+%swig_optional_int(uint32_t, PyLong_AsUnsignedLongLong, unsigned long long, wide > UINT32_MAX)
+%swig_optional(uint64_t, PyLong_AsUnsignedLongLong, PyLong_Check)
+%swig_optional_int(uintptr_t, PyLong_AsUnsignedLongLong, unsigned long long, wide > UINTPTR_MAX)
+%swig_optional(float, PyFloat_AsDouble, PyNumber_Check)
+%swig_optional(double, PyFloat_AsDouble, PyNumber_Check)
+#endif
+
+// Errors raised on the Rust side are stashed away, and turned into
+// target-language exceptions once the extern returns. Conflicting borrows
+// raise a `BorrowError` in Python, a subclass of `RuntimeError`.
+%{{
+    enum swig_error_kind {{ SWIG_ERROR_RUNTIME, SWIG_ERROR_BORROW }};
+#ifdef SWIGPYTHON
+    static PyObject *swig_py_borrow_error = NULL;
+#endif
+%}}
+#ifdef SWIGPYTHON
+%init %{{
+    swig_py_borrow_error = PyErr_NewException((char *) \"{name}.BorrowError\", PyExc_RuntimeError, NULL);
+    Py_INCREF(swig_py_borrow_error);
+    PyModule_AddObject(m, \"BorrowError\", swig_py_borrow_error);
+%}}
+%pythoncode %{{
+BorrowError = _{name}.BorrowError
+%}}
+#endif
+%exception {{
+    $action
+    uint32_t swig_err_kind = ffi::swig_last_error_kind();
+    if (char *swig_err = ffi::swig_take_error()) {{
+        std::string swig_msg(swig_err);
+        ffi::free_string(swig_err);
+#ifdef SWIGPYTHON
+        if (swig_err_kind == SWIG_ERROR_BORROW) {{
+            PyErr_SetString(swig_py_borrow_error, swig_msg.c_str());
+            SWIG_fail;
+        }}
+#endif
+        SWIG_exception(SWIG_RuntimeError, swig_msg.c_str());
+    }}
+}}
 
-%typemap(newfree) char * \"free_string($1);\";
+#ifdef SWIGPYTHON
+// Constructors can also be picked by keyword arguments, for those which
+// SWIG's overloading can't tell apart.
+%pythoncode %{{
+def _swig_add_ctor(cls, names, make):
+    make = getattr(cls, make)
+    ctors = cls.__dict__.get(\"_swig_ctors\")
+    if ctors is None:
+        ctors = cls._swig_ctors = []
+        init = cls.__init__
+        def __init__(self, *args, **kwargs):
+            if not kwargs:
+                return init(self, *args)
+            for names, make in ctors:
+                rest = names[len(args):]
+                if len(args) + len(kwargs) == len(names) and sorted(rest) == sorted(kwargs):
+                    self.this = make(*(args + tuple(kwargs[n] for n in rest))).this
+                    return
+            raise TypeError(\"no constructor of %s takes the arguments (%s)\" % (cls.__name__, \", \".join(sorted(kwargs))))
+        cls.__init__ = __init__
+    ctors.append((names, make))
+%}}
 
+// Awaitable functions return a task, which is wrapped in an `asyncio`
+// future. Rust notifies the task from the executor thread once it
+// completes, and the result is taken on the event loop. The task keeps the
+// object the future borrows from alive until then. The loop is looked up
+// before calling into Rust, so nothing is spawned without one.
+%pythoncode %{{
+def _swig_running_loop():
+    import asyncio
+    return asyncio.get_running_loop()
+
+def _swig_awaitable(task, owner, loop):
+    future = loop.create_future()
+    task._swig_owner = owner
+    def resolve():
+        if future.cancelled():
+            return
+        try:
+            future.set_result(task.result())
+        except Exception as e:
+            future.set_exception(e)
+    task.on_done(lambda: loop.call_soon_threadsafe(resolve))
+    return future
+%}}
+#endif
 
 %{{
     namespace ffi {{
@@ -515,23 +3285,56 @@ pub fn gen_swig(pkg_name: &str, src: &str) {
 
     using namespace ffi;
 
+    // Copy an owned string returned from Rust, and free the original.
+    static std::string swig_take_string(char *s) {{
+        std::string res = s ? s : \"\";
+        ffi::free_string(s);
+        return res;
+    }}
+
+#ifdef SWIGPYTHON
+    // Release a Python callable once Rust drops the closure wrapping it.
+    static void swig_py_callback_drop(void *user_data) {{
+        PyGILState_STATE gil = PyGILState_Ensure();
+        Py_DECREF((PyObject *) user_data);
+        PyGILState_Release(gil);
+    }}
+
+    // Record the exception raised by a Python callback, to be raised again
+    // once control returns from Rust.
+    static void swig_py_callback_error() {{
+        PyObject *type, *value, *tb;
+        PyErr_Fetch(&type, &value, &tb);
+        PyObject *msg = value ? PyObject_Str(value) : NULL;
+        const char *s = msg ? PyUnicode_AsUTF8(msg) : NULL;
+        ffi::swig_raise_error(s ? s : \"exception raised in callback\");
+        Py_XDECREF(msg);
+        Py_XDECREF(type);
+        Py_XDECREF(value);
+        Py_XDECREF(tb);
+        PyErr_Clear();
+    }}
+#endif
+
     namespace {name} {{
 ", name=pkg_name).as_bytes()).unwrap();
 
-    let syntax = syn::parse_file(&src).expect("Unable to parse file");
+    let syntax = syn::parse_file(src).expect("Unable to parse file");
     trace!("Syntax: {:#?}", syntax);
     let mut hdr = String::new();
+    let mut stubs = Vec::<(Vec<String>, String)>::new();
 
     // SWIG code is inside doc comments:
     // #[doc = "<swig code here>"]
     // struct __SWIG_INJECT_Foo;
     //
-    // So we extract this out.
+    // So we extract this out, keeping track of the module it came from.
 
-    syntax.items.iter().flat_map(|i| {
+    let items = module_items(&syntax.items, &[]);
+    let payloads: Vec<(Vec<String>, syn::Attribute)> = items.iter().flat_map(|(path, i)| {
         // Extract out all of the attributes which are attached to structs/functions
         // starting with "__SWIG_INJECT"
-        match i {
+        let attrs = match i {
             syn::Item::Impl(ii) => {
                 ii.items.iter().fold(Vec::new(), |mut acc, ref ii| {
                     match ii {
@@ -556,13 +3359,31 @@ pub fn gen_swig(pkg_name: &str, src: &str) {
                 }
             },
             _ => Vec::new()
+        };
+        attrs.into_iter().map(move |a| (path.clone(), a))
+    }).collect();
+
+    // Nested modules become nested namespaces, which are pulled into the
+    // package namespace so the classes can still refer to each other.
+    let mut modules = Vec::<Vec<String>>::new();
+    for (path, _) in payloads.iter() {
+        if !path.is_empty() && !modules.contains(path) {
+            modules.push(path.clone());
         }
-    }).for_each(|ref attr| {
+    }
+    let usings: String = modules.iter().map(|path| {
+        let open: String = path.iter().map(|m| format!("namespace {} {{ ", m)).collect();
+        format!("{}{}\n    using namespace {};\n", open, "} ".repeat(path.len()), path.join("::"))
+    }).collect();
+    tmp_file.write_all(usings.as_bytes()).unwrap();
+    hdr += &usings;
+
+    payloads.iter().for_each(|(path, attr)| {
         match attr.interpret_meta() {
-            Some(syn::Meta::NameValue(ref mnv)) if &mnv.ident.to_string() == "doc" => {
+            Some(syn::Meta::NameValue(ref mnv)) if mnv.ident == "doc" => {
                 // Extract out the doc comment for these attributes
                 if let syn::Lit::Str(ref ls) = mnv.lit {
-                    let mut swig_class = ls.value().replace("\\n", "\n");
+                    let swig_class = ls.value().replace("\\n", "\n");
                     let prefix_offset = swig_class.find(SwigTag::CodeStart.to_str()).expect("no code prefix") + SwigTag::CodeStart.len();
                     let suffix_offset = swig_class.find(SwigTag::CodeEnd.to_str()).expect("no code suffix");
                     let final_class = &swig_class[prefix_offset..suffix_offset];
@@ -571,17 +3392,32 @@ pub fn gen_swig(pkg_name: &str, src: &str) {
                     let suffix_offset = swig_class.find(SwigTag::HdrEnd.to_str()).expect("no header suffix");
                     let final_hdr = &swig_class[prefix_offset..suffix_offset];
 
-                    tmp_file.write_all(&final_class.replace("\\n", "\n").as_bytes()).unwrap();
-                    hdr += &final_hdr.replace("\\n", "\n");
+                    tmp_file.write_all(in_namespaces(path, &final_class.replace("\\n", "\n")).as_bytes()).unwrap();
+                    // `%pythoncode` isn't preprocessed, so the extension module
+                    // name is filled in here.
+                    hdr += &in_namespaces(path, &final_hdr.replace("\\n", "\n")
+                        .replace(SwigTag::PyModule.to_str(), &format!("_{}", pkg_name)));
                     debug!("{}", final_hdr);
                     debug!("{}", final_class);                    
 
+                    // Any number of Python type stubs may follow.
+                    let mut rest = &swig_class[..];
+                    while let Some(prefix_offset) = rest.find(SwigTag::PyiStart.to_str()) {
+                        let suffix_offset = rest.find(SwigTag::PyiEnd.to_str()).expect("no stub suffix");
+                        stubs.push((path.clone(), rest[prefix_offset + SwigTag::PyiStart.len()..suffix_offset].to_string()));
+                        rest = &rest[suffix_offset + SwigTag::PyiEnd.len()..];
+                    }
+
                 }
             },
             _ => ()
             }
     });
 
+    // `%pythoncode` refers to classes by their Python name, which is only
+    // known from the stubs of the class, as it may be renamed.
+    let hdr = resolve_py_classes(&hdr, &stubs);
+
     tmp_file.write_all(format!("\
     }}
 %}}
@@ -593,11 +3429,506 @@ namespace {name} {{
 %ignore {inject};
 %include \"bindings.h\";
 ", name=pkg_name, header=hdr, inject=SwigTag::SwigInject).as_bytes()).unwrap();
+
+    // Plain `extern \"C\"` functions are exported through `bindings.h`.
+    let class_of = |ty: &syn::Type| swig_class_name(ty);
+    for (path, item) in items.iter() {
+        match item {
+            syn::Item::Fn(f) if f.abi.is_c() && !f.ident.to_string().starts_with(SwigTag::SwigInject.to_str()) => {
+                let params: Vec<String> = f.decl.inputs.iter().filter_map(|arg| match arg {
+                    syn::FnArg::Captured(syn::ArgCaptured { pat: syn::Pat::Ident(pi), ty, .. }) => {
+                        Some(format!("{}: {}", pi.ident, py_type(ty, &class_of)))
+                    },
+                    _ => None,
+                }).collect();
+                let ret = match f.decl.output {
+                    syn::ReturnType::Type(_, ref ty) => py_type(ty, &class_of),
+                    syn::ReturnType::Default => "None".to_string(),
+                };
+                stubs.push((path.clone(), format!("module\ndef {}({}) -> {}: ...", f.ident, params.join(", "), ret)));
+            },
+            _ => (),
+        }
+    }
+
+    // Arrange the Python names of items in nested modules.
+    let mut module_names = Vec::<(Vec<String>, Vec<String>)>::new();
+    for (path, stub) in stubs.iter().filter(|(path, _)| !path.is_empty()) {
+        let name = match pyi_module_name(stub) {
+            Some(name) => name,
+            None => continue,
+        };
+        match module_names.iter().position(|(p, _)| p == path) {
+            Some(idx) if module_names[idx].1.contains(&name) => (),
+            Some(idx) => module_names[idx].1.push(name),
+            None => module_names.push((path.clone(), vec![name])),
+        }
+    }
+    let py_tuple = |items: &[String]| format!("({},)", items.iter().map(|i| format!("{:?}", i)).collect::<Vec<_>>().join(", "));
+    let layout: Vec<String> = module_names.iter()
+        .map(|(path, names)| format!("({}, {})", py_tuple(path), py_tuple(names))).collect();
+    let layout_code = match py_modules {
+        _ if layout.is_empty() => None,
+        PyModules::Flat => None,
+        PyModules::Prefix => Some("\
+# Items of nested Rust modules are prefixed with the module path.
+def _swig_layout(path, names):
+    for name in names:
+        globals()[\"_\".join(path + (name,))] = globals().pop(name)"),
+        PyModules::Submodules => Some("\
+# Items of nested Rust modules are also available from submodules.
+import sys as _swig_sys, types as _swig_types
+def _swig_layout(path, names):
+    parent = _swig_sys.modules[__name__]
+    for i in range(len(path)):
+        full = \".\".join((__name__,) + path[:i + 1])
+        if full not in _swig_sys.modules:
+            _swig_sys.modules[full] = _swig_types.ModuleType(full)
+            setattr(parent, path[i], _swig_sys.modules[full])
+        parent = _swig_sys.modules[full]
+    for name in names:
+        setattr(parent, name, globals()[name])"),
+    };
+    if let Some(code) = layout_code {
+        tmp_file.write_all(format!("
+#ifdef SWIGPYTHON
+%pythoncode %{{
+{code}
+for _swig_path, _swig_names in ({layout},):
+    _swig_layout(_swig_path, _swig_names)
+%}}
+#endif
+", code=code, layout=layout.join(", ")).as_bytes()).unwrap();
+    }
+    write_pyi(pkg_name, &stubs, py_modules, &module_names);
+}
+
+/// Replace `__SWIG_PY_CLASS(Foo)` by the Python name of the class `Foo`.
+fn resolve_py_classes(code: &str, stubs: &[(Vec<String>, String)]) -> String {
+    let py_name = |class: &str| stubs.iter().filter_map(|(_, stub)| {
+        let mut words = stub.lines().next()?.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("class"), Some(c), Some(py_name)) if c == class => Some(py_name.to_string()),
+            _ => None,
+        }
+    }).next().unwrap_or_else(|| class.to_string());
+    let mut out = String::new();
+    let mut rest = code;
+    while let Some(start) = rest.find(SwigTag::PyClass.to_str()) {
+        let open = start + SwigTag::PyClass.len();
+        let end = open + rest[open..].find(')').expect("unterminated class name");
+        out += &rest[..start];
+        out += &py_name(&rest[open + 1..end]);
+        rest = &rest[end + 1..];
+    }
+    out + rest
+}
+
+/// All items of the crate, with the path of the inline module they are in.
+fn module_items<'a>(items: &'a [syn::Item], path: &[String]) -> Vec<(Vec<String>, &'a syn::Item)> {
+    let mut out = Vec::new();
+    for item in items {
+        match item {
+            syn::Item::Mod(syn::ItemMod { ident, content: Some((_, items)), .. }) => {
+                let mut path = path.to_vec();
+                path.push(ident.to_string());
+                out.extend(module_items(items, &path));
+            },
+            _ => out.push((path.to_vec(), item)),
+        }
+    }
+    out
+}
+
+/// Wrap code in the C++ namespaces of a module path.
+fn in_namespaces(path: &[String], code: &str) -> String {
+    if path.is_empty() || code.trim().is_empty() {
+        return code.to_string();
+    }
+    let open: String = path.iter().map(|m| format!("namespace {} {{ ", m)).collect();
+    format!("{}\n{}\n{}", open, code, "} ".repeat(path.len()))
+}
+
+
+/// Write the Python type stubs collected from the injected code to
+/// `<module>.pyi`.
+/// In the `Submodules` layout, the stubs are a package with a stub for each
+/// submodule, listed in `module_names` with the names they re-export.
+fn write_pyi(pkg_name: &str, stubs: &[(Vec<String>, String)], py_modules: PyModules,
+             module_names: &[(Vec<String>, Vec<String>)]) {
+    // Wrapper classes, with their Python name and stubs of their methods.
+    let mut classes = Vec::<(String, String, Vec<String>)>::new();
+    let mut functions = Vec::<String>::new();
+    for (path, stub) in stubs {
+        let prefix = if py_modules == PyModules::Prefix && !path.is_empty() {
+            format!("{}_", path.join("_"))
+        } else {
+            String::new()
+        };
+        let (scope, body) = stub.split_at(stub.find('\n').expect("no stub scope"));
+        let body = body[1..].trim_end().to_string();
+        let mut words = scope.split_whitespace();
+        let kind = words.next();
+        if kind == Some("module") {
+            functions.push(if let Some(def) = body.strip_prefix("def ") {
+                format!("def {}{}", prefix, def)
+            } else {
+                prefix + &body
+            });
+            continue;
+        }
+        let class = words.next().expect("no stub class").to_string();
+        let idx = match classes.iter().position(|c| c.0 == class) {
+            Some(idx) => idx,
+            None => {
+                classes.push((class.clone(), class.clone(), Vec::new()));
+                classes.len() - 1
+            },
+        };
+        match kind {
+            Some("class") => classes[idx].1 = prefix + words.next().expect("no Python class name"),
+            Some("member") => classes[idx].2.push(body),
+            _ => panic!("invalid stub scope `{}`", scope),
+        }
+    }
+
+    // Classes are referred to as `$Foo$`, since they may be renamed.
+    let resolve = |stub: &str| -> String {
+        let mut out = String::new();
+        let mut rest = stub;
+        while let Some(start) = rest.find('$') {
+            let end = start + 1 + rest[start + 1..].find('$').expect("unterminated class name");
+            out += &rest[..start];
+            let class = &rest[start + 1..end];
+            out += classes.iter().find(|c| c.0 == class).map_or("Any", |c| c.1.as_str());
+            rest = &rest[end + 1..];
+        }
+        out + rest
+    };
+
+    let mut pyi = format!("\
+# Type stubs for the `{}` module, generated by swiggen.
+from typing import Any, Awaitable, Callable, Iterator, List, Optional, Tuple, overload
+", pkg_name);
+    for f in functions.iter() {
+        pyi += &format!("\n{}\n", resolve(f));
+    }
+    for (_, py_name, members) in classes.iter() {
+        pyi += &format!("\nclass {}:\n", py_name);
+        if members.is_empty() {
+            pyi += "    ...\n";
+        }
+        // Overloads need to be next to each other.
+        let mut names = Vec::<String>::new();
+        for m in members.iter() {
+            if !names.contains(&stub_def_name(m)) {
+                names.push(stub_def_name(m));
+            }
+        }
+        for name in names.iter() {
+            let overloads: Vec<&String> = members.iter().filter(|m| &stub_def_name(m) == name).collect();
+            let mut seen = Vec::<Vec<String>>::new();
+            for m in overloads.iter() {
+                let mut stub = resolve(m);
+                // Overloads taking the same types can only be told apart by
+                // keyword, which is how they are picked at runtime.
+                let (mut params, tys) = stub_params(&stub);
+                if seen.contains(&tys) {
+                    let at = params.iter().take_while(|p| *p == "self").count();
+                    params.insert(at, "*".to_string());
+                    let start = stub.find('(').expect("no parameters in stub");
+                    let end = start + stub_params_len(&stub[start..]);
+                    stub = format!("{}({}){}", &stub[..start], params.join(", "), &stub[end..]);
+                }
+                seen.push(tys);
+                if overloads.len() > 1 {
+                    stub = format!("@overload\n{}", stub);
+                }
+                for line in stub.lines() {
+                    pyi += &format!("    {}\n", line);
+                }
+            }
+        }
+    }
+    if py_modules != PyModules::Submodules || module_names.is_empty() {
+        let mut pyi_file = File::create(format!("{}.pyi", pkg_name)).unwrap();
+        pyi_file.write_all(pyi.as_bytes()).unwrap();
+        return;
+    }
+
+    let mut packages = vec![Vec::<String>::new()];
+    for (path, _) in module_names {
+        for i in 1..path.len() + 1 {
+            if !packages.iter().any(|p| p[..] == path[..i]) {
+                packages.push(path[..i].to_vec());
+            }
+        }
+    }
+    for package in packages.iter() {
+        let mut code = if package.is_empty() {
+            pyi.clone()
+        } else {
+            format!("# Items of `{}.{}`, which are also in the top-level module.\n", pkg_name, package.join("."))
+        };
+        if let Some((_, names)) = module_names.iter().find(|(p, _)| p == package) {
+            for name in names.iter() {
+                code += &format!("from {} import {} as {}\n", pkg_name, name, name);
+            }
+        }
+        let children = packages.iter().filter(|p| p.len() == package.len() + 1 && p.starts_with(package));
+        for child in children {
+            code += &format!("\nfrom . import {} as {}\n", child[package.len()], child[package.len()]);
+        }
+        let dir = package.iter().fold(std::path::PathBuf::from(pkg_name), |dir, m| dir.join(m));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut pyi_file = File::create(dir.join("__init__.pyi")).unwrap();
+        pyi_file.write_all(code.as_bytes()).unwrap();
+    }
+}
+
+/// The name defined by a function or constant stub. Constants are stubbed
+/// as `NAME: int`.
+fn stub_def_name(stub: &str) -> String {
+    match stub.find("def ") {
+        Some(start) => {
+            let end = start + 4 + stub[start + 4..].find('(').expect("no parameters in stub");
+            stub[start + 4..end].to_string()
+        },
+        None => stub[..stub.find(':').expect("no type in stub")].to_string(),
+    }
+}
+
+/// Length of the parenthesised parameter list at the start of `s`.
+fn stub_params_len(s: &str) -> usize {
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            _ => {},
+        }
+        if depth == 0 {
+            return i + 1;
+        }
+    }
+    panic!("unterminated parameters in stub `{}`", s)
+}
+
+/// The parameters of a `def` stub, and their types (without `self`).
+fn stub_params(stub: &str) -> (Vec<String>, Vec<String>) {
+    let start = match stub.find('(') {
+        Some(start) => start,
+        None => return (Vec::new(), Vec::new()),
+    };
+    let list = &stub[start + 1..start + stub_params_len(&stub[start..]) - 1];
+    let mut params = Vec::new();
+    let (mut depth, mut from) = (0, 0);
+    for (i, c) in list.char_indices() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                params.push(list[from..i].trim().to_string());
+                from = i + 1;
+            },
+            _ => {},
+        }
+    }
+    if !list[from..].trim().is_empty() {
+        params.push(list[from..].trim().to_string());
+    }
+    let tys = params.iter().filter_map(|p| p.find(':').map(|i| p[i + 1..].trim().to_string())).collect();
+    (params, tys)
+}
+
+/// The Python name a stub adds to the module, if it's a class, function or
+/// constant rather than a member of a class.
+fn pyi_module_name(stub: &str) -> Option<String> {
+    let (scope, body) = stub.split_at(stub.find('\n').expect("no stub scope"));
+    let mut words = scope.split_whitespace();
+    match words.next() {
+        Some("module") => Some(stub_def_name(&body[1..])),
+        Some("class") => words.nth(1).map(|py_name| py_name.to_string()),
+        _ => None,
+    }
+}
+
+/// The traits which can be used in `#[swig_derive(...)]`.
+const SWIG_DERIVES: &[&str] = &["Default", "Clone", "Debug", "Display", "PartialEq", "Eq", "Hash", "PartialOrd", "Ord"];
+
+/// The Python type of a Rust type, for the `.pyi` stubs. Wrapper classes
+/// are named with `class`, and written as `$Foo$` to be replaced with their
+/// (possibly renamed) Python name once all classes are known.
+fn py_type(ty: &syn::Type, class: &dyn Fn(&syn::Type) -> String) -> String {
+    if let Some(inner) = option_inner(ty) {
+        return format!("Optional[{}]", py_type(inner, class));
+    }
+    if str_ret_kind(ty).is_some() {
+        return "str".to_string();
+    }
+    if bytes_kind(ty).is_some() {
+        return "bytes".to_string();
+    }
+    if let Some(elem) = vec_elem(ty) {
+        return format!("List[{}]", py_type(elem, class));
+    }
+    if let Some(elems) = tuple_elems(ty) {
+        let elems: Vec<String> = elems.iter().map(|e| py_type(e, class)).collect();
+        return format!("Tuple[{}]", elems.join(", "));
+    }
+    if let Some(item) = iter_item(ty) {
+        return format!("Iterator[{}]", py_type(&item, class));
+    }
+    if let Some((_, inner)) = shared_inner(ty) {
+        return py_type(inner, class);
+    }
+    if let Some(cb) = callback_sig(ty) {
+        let inputs: Vec<String> = cb.inputs.iter().map(|i| py_type(i, class)).collect();
+        let output = cb.output.as_ref().map_or("None".to_string(), |o| py_type(o, class));
+        return format!("Callable[[{}], {}]", inputs.join(", "), output);
+    }
+    if let Some((_, name)) = dyn_trait(ty) {
+        return format!("${}$", name);
+    }
+    match ty {
+        syn::Type::Reference(r) => return py_type(&r.elem, class),
+        syn::Type::Paren(p) => return py_type(&p.elem, class),
+        // C strings, as in plain `extern "C"` functions.
+        syn::Type::Ptr(p) if type_name(&p.elem) == "c_char" => return "str".to_string(),
+        _ => {},
+    }
+    match ty::Type::load(ty) {
+        Ok(None) => "None".to_string(),
+        Ok(Some(ty::Type::Primitive(_))) => match type_name(ty).as_str() {
+            "bool" => "bool",
+            "f32" | "f64" => "float",
+            _ => "int",
+        }.to_string(),
+        Ok(Some(ty::Type::Path(_))) => format!("${}$", class(ty)),
+        _ => "Any".to_string(),
+    }
+}
+
+/// Wrap a `.pyi` stub for a single function or constant in the tags for `gen_swig` to
+/// pick it up. Methods give the class they belong to.
+fn pyi_stub(class: Option<&str>, stub: &str) -> String {
+    let scope = class.map_or("module".to_string(), |c| format!("member {}", c));
+    format!("{}{}\n{}\n{}", SwigTag::PyiStart, scope, stub, SwigTag::PyiEnd)
+}
+
+/// Declare a class in the `.pyi` stubs, with its name in Python.
+fn pyi_class(class: &str, py_name: &str) -> String {
+    format!("{}class {} {}\n{}", SwigTag::PyiStart, class, py_name, SwigTag::PyiEnd)
 }
 
+/// Collect the `///` documentation of an item, without the leading space on
+/// each line.
+fn get_docs(attrs: &[syn::Attribute]) -> Option<String> {
+    let lines: Vec<String> = attrs.iter().filter_map(|a| a.interpret_meta())
+        .filter_map(|m| match m {
+            syn::Meta::NameValue(ref mnv) if mnv.ident == "doc" => match mnv.lit {
+                syn::Lit::Str(ref ls) => Some(ls.value()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .map(|line| match line.strip_prefix(' ') {
+            Some(stripped) => stripped.to_string(),
+            None => line,
+        })
+        .collect();
+    let docs = lines.join("\n").trim().to_string();
+    if docs.is_empty() {
+        None
+    } else {
+        Some(docs)
+    }
+}
+
+/// The documentation of a struct, with its documented fields appended, as
+/// they have no wrapper of their own.
+fn struct_docs(ast: &syn::DeriveInput) -> Option<String> {
+    let fields: Vec<String> = match ast.data {
+        syn::Data::Struct(syn::DataStruct { fields: syn::Fields::Named(ref fields), .. }) => {
+            fields.named.iter().filter_map(|f| {
+                let docs = get_docs(&f.attrs)?;
+                Some(format!("    {}: {}", f.ident.as_ref()?, docs.replace('\n', "\n        ")))
+            }).collect()
+        },
+        _ => Vec::new(),
+    };
+    let docs = get_docs(&ast.attrs);
+    if fields.is_empty() {
+        return docs;
+    }
+    Some(format!("{}Fields:\n{}", docs.map_or(String::new(), |d| d + "\n\n"), fields.join("\n")))
+}
+
+/// Quote a string for SWIG, which unescapes it like a C string literal.
+/// Octal escapes are used, as `\n` is taken as a newline in the payload.
+fn swig_str(s: &str) -> String {
+    let escaped: String = s.chars().map(|c| match c {
+        '\\' => "\\134".to_string(),
+        '"' => "\\042".to_string(),
+        '\n' => "\\012".to_string(),
+        c => c.to_string(),
+    }).collect();
+    format!("\"{}\"", escaped)
+}
+
+/// SWIG declarations to attach documentation to `target`, e.g. `Foo::bar`.
+/// Python gets a docstring, and Java and C# get doc comments. The docs are
+/// passed as escaped strings, so they can contain anything.
+fn swig_docs(target: &str, docs: &str, is_class: bool) -> String {
+    let javadoc: String = docs.lines().map(|l| format!(" * {}\n", l.replace("*/", "* /"))).collect();
+    let csdoc: String = docs.lines().map(|l| {
+        format!("/// {}\n", l.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;"))
+    }).collect();
+    let (java, cs) = if is_class {
+        (format!("%typemap(javaclassmodifiers) {} {};", target, swig_str(&format!("\n/**\n{} */\npublic class", javadoc))),
+         format!("%typemap(csclassmodifiers) {} {};", target, swig_str(&format!("\n/// <summary>\n{}/// </summary>\npublic class", csdoc))))
+    } else {
+        (format!("%javamethodmodifiers {} {};", target, swig_str(&format!("\n/**\n{} */\npublic", javadoc))),
+         format!("%csmethodmodifiers {} {};", target, swig_str(&format!("\n/// <summary>\n{}/// </summary>\npublic", csdoc))))
+    };
+    // The docstring ends up in triple quotes in Python.
+    let mut docs = docs.replace("\"\"\"", "\\\"\\\"\\\"");
+    if docs.ends_with('"') {
+        docs.push(' ');
+    }
+    format!("
+%feature(\"docstring\") {target} {docs};
+#ifdef SWIGJAVA
+{java}
+#endif
+#ifdef SWIGCSHARP
+{cs}
+#endif
+", target=target, docs=swig_str(&docs), java=java, cs=cs)
+}
 
-/// Extract out any `derive(Foo)` attributes.
+/// Extract out any `swig_derive(Foo)` attributes.
+/// Panics on any unsupported trait. `Eq` and `Ord` add nothing over
+/// `PartialEq` and `PartialOrd`, so are folded into them.
 fn get_derives(attrs: &[syn::Attribute]) -> Vec<String> {
+    let mut derives: Vec<String> = Vec::new();
+    for d in get_derive_names(attrs) {
+        if !SWIG_DERIVES.contains(&d.as_str()) {
+            panic!("Unsupported trait `{}` in `swig_derive`, expected one of: {}", d, SWIG_DERIVES.join(", "));
+        }
+        let d = match d.as_str() {
+            "Eq" => "PartialEq".to_string(),
+            "Ord" => "PartialOrd".to_string(),
+            _ => d,
+        };
+        if !derives.contains(&d) {
+            derives.push(d);
+        }
+    }
+    derives
+}
+
+fn get_derive_names(attrs: &[syn::Attribute]) -> Vec<String> {
     attrs.iter().filter_map(|a| a.interpret_meta())
           .filter_map(|a| {
             if let syn::Meta::List(ml) = a {
@@ -605,24 +3936,104 @@ fn get_derives(attrs: &[syn::Attribute]) -> Vec<String> {
             } else {
                 None
             }
-          }).filter(|ml| ml.ident.to_string() == "swig_derive")
+          }).filter(|ml| ml.ident == "swig_derive")
           .flat_map(|ml| ml.nested)
           .filter_map(|nm| {
-            if let syn::NestedMeta::Meta(m) = nm {
-                if let syn::Meta::Word(w) = m {
-                    Some(w.to_string())
-                } else {
-                    None
-                }
+            if let syn::NestedMeta::Meta(syn::Meta::Word(w)) = nm {
+                Some(w.to_string())
             } else {
                 None
             }
         }).collect()
 }
 
+/// Extract the instances listed in any `swig_instantiate(..)` attributes.
+fn get_instances(attrs: &[syn::Attribute]) -> Vec<Instance> {
+    attrs.iter()
+        .filter(|a| a.path.clone().into_token_stream().to_string() == "swig_instantiate")
+        .flat_map(|a| {
+            let insts: Instances = syn::parse2(a.tts.clone())
+                .expect("failed to parse `swig_instantiate` attribute");
+            insts.0
+        }).collect()
+}
+
 /// Parse a Rust file to extract any extern "C" functions or
 /// `#[swiggen]`-annotated methods and move these out of the impl block.
+/// Associated consts marked `#[swiggen(Foo)]` are exported as constants.
+/// With `#[swiggen(Foo)]` on the impl block itself, all of its public
+/// methods and consts are exported, except those marked `#[swig(skip)]`.
+/// For a trait impl, the methods are added to the class of `Foo` and call
+/// the implementation through `<Foo as Trait>::method`.
 pub fn split_out_externs(ast: &syn::ItemImpl) -> TokenStream {
+    let impl_args = ast.attrs.iter().find(|a| is_swiggen(a)).map(parse_args);
+    extern_impl_items(impl_args.as_ref(), ast)
+}
+
+fn is_swiggen(attr: &syn::Attribute) -> bool {
+    attr.path.clone().into_token_stream().to_string() == "swiggen"
+}
+
+/// `instantiate(T = u32)` isn't valid meta syntax, so parse the attribute
+/// tokens directly.
+fn parse_args(attr: &syn::Attribute) -> Args {
+    syn::parse2(attr.tts.clone()).expect("Invalid `#[swiggen(..)]` arguments")
+}
+
+/// Export the methods of a `#[swiggen(Foo)]` annotated trait impl, e.g.
+/// `impl From<u32> for Foo`, which (unlike inherent impls) doesn't need
+/// `swiggen_hack!`.
+pub fn impl_extern_impl(args: &Args, ast: &syn::ItemImpl) -> TokenStream {
+    extern_impl_items(Some(args), ast)
+}
+
+fn extern_impl_items(impl_args: Option<&Args>, ast: &syn::ItemImpl) -> TokenStream {
+    if matches!(impl_args, Some(args) if args.base.is_none()) {
+        panic!("`#[swiggen]` on an impl block needs the type name. Try: `#[swiggen(Foo)]`");
+    }
+
+    // Methods of trait impls are called as `<Foo as Trait>::method`, and are
+    // all public.
+    let trait_ = ast.trait_.as_ref().map(|(_, path, _)| path);
+    // Instances of a generic type are bound in the order of its parameters,
+    // e.g. `K, V` for `impl<K, V> Map<K, V>`.
+    let base_params: Vec<syn::Ident> = match *ast.self_ty {
+        syn::Type::Path(ref tp) => match tp.path.segments.iter().last().map(|seg| &seg.arguments) {
+            Some(syn::PathArguments::AngleBracketed(ab)) => ab.args.iter().filter_map(|arg| match arg {
+                syn::GenericArgument::Type(syn::Type::Path(tp)) if tp.qself.is_none() && tp.path.segments.len() == 1 => {
+                    tp.path.segments.iter().next().map(|seg| seg.ident.clone())
+                },
+                _ => None,
+            }).collect(),
+            _ => vec![],
+        },
+        _ => vec![],
+    };
+    let exported = |vis: &syn::Visibility| match vis {
+        syn::Visibility::Public(_) => true,
+        _ => trait_.is_some(),
+    };
+
+    let export = |iim: &syn::ImplItemMethod, asyncness: bool| {
+        if SwigOptions::from_attrs(&iim.attrs).skip {
+            return None;
+        }
+        let mut ret = None;
+        for attr in iim.attrs.iter().filter(|a| is_swiggen(a)) {
+            let args = parse_args(attr);
+            if args.base.is_none() {
+                panic!("`#[swiggen]` on method `{}` needs the type name. Try: `#[swiggen(Foo)]`", iim.sig.ident);
+            }
+            ret = Some(impl_extern_method(&args, trait_, &base_params, &iim_to_itemfn(iim.clone()), asyncness));
+        }
+        match (ret, impl_args) {
+            (None, Some(args)) if exported(&iim.vis) => {
+                Some(impl_extern_method(args, trait_, &base_params, &iim_to_itemfn(iim.clone()), asyncness))
+            },
+            (ret, _) => ret,
+        }
+    };
+
     let mut tokens = TokenStream::new();
     tokens.append_all(ast.items.iter().filter_map(|item| {
         match item {
@@ -630,30 +4041,47 @@ pub fn split_out_externs(ast: &syn::ItemImpl) -> TokenStream {
                 if iim.sig.abi.is_c(){
                     Some(item.into_token_stream())
                 } else {
-                    let mut ret = None;
-                    for attr in iim.attrs.iter().filter_map(|a| a.interpret_meta()) {
-                        match attr {
-                            syn::Meta::List(ml) => if ml.ident == syn::Ident::new("swiggen", Span::call_site()) {
-                                if let Some(v) = ml.nested.first().map(|p| p.into_value()) {
-                                    match v {
-                                        syn::NestedMeta::Meta(m) => {
-                                            let base_name = Some(m.name());
-                                            ret = Some(impl_extern_fn(&base_name, &iim_to_itemfn(iim.clone())));
-                                        },
-                                        _ => {}
-                                    }
-                                }
-                            },
-                            _ => {}
-                        }
-                    }
-                    ret
+                    export(iim, false)
                 }
             },
+            syn::ImplItem::Verbatim(iiv) => parse_async_fn(&iiv.tts).and_then(|iim| export(&iim, true)),
+            syn::ImplItem::Const(iic) => {
+                let explicit = iic.attrs.iter().filter(|a| is_swiggen(a)).filter_map(|a| parse_args(a).base).next();
+                let base = match (explicit, impl_args) {
+                    (Some(base), _) => base,
+                    (None, Some(args)) if exported(&iic.vis) => {
+                        if !args.instances.is_empty() && !SwigOptions::from_attrs(&iic.attrs).skip {
+                            panic!("Cannot export associated const `{}` of a generic type. Try: `#[swig(skip)]`", iic.ident);
+                        }
+                        args.base.clone().unwrap()
+                    },
+                    _ => return None,
+                };
+                Some(impl_extern_const(Some(&base), trait_, &iic.ident, &iic.ty, &iic.attrs))
+            },
             _ => None,
         }
     }));
 
+    // The methods and consts have been exported above, so the attributes are no longer
+    // needed (and `#[swig(..)]` means nothing to the compiler).
+    let mut ast = ast.clone();
+    ast.attrs.retain(|a| !is_swiggen(a));
+    let keep = |a: &syn::Attribute| !is_swig_attr(a) && !is_swiggen(a);
+    for item in ast.items.iter_mut() {
+        match item {
+            syn::ImplItem::Method(iim) => iim.attrs.retain(keep),
+            syn::ImplItem::Verbatim(iiv) => {
+                if let Some(mut iim) = parse_async_fn::<syn::ImplItemMethod>(&iiv.tts) {
+                    iim.attrs.retain(keep);
+                    iiv.tts = add_async(&iim);
+                }
+            },
+            syn::ImplItem::Const(iic) => iic.attrs.retain(|a| !is_swig_attr(a) && !is_swiggen(a)),
+            _ => (),
+        }
+    }
+
     quote!{
         #ast
 
@@ -661,15 +4089,259 @@ pub fn split_out_externs(ast: &syn::ItemImpl) -> TokenStream {
     }
 }
 
-#[derive(Debug)]
-pub struct Args(pub Option<syn::Ident>);
+/// Options for an exported item, given with `#[swig(..)]`, e.g.
+/// `#[swig(constructor)]` or `#[swig(name = "load")]`.
+#[derive(Debug, Default)]
+pub struct SwigOptions {
+    /// Name to use in the target language instead of the Rust identifier.
+    pub name: Option<String>,
+    /// Leave the item out of the bindings.
+    pub skip: bool,
+    /// Export an associated function as an additional constructor.
+    pub constructor: bool,
+    /// Default values for parameters, as `(name, literal)` pairs.
+    pub defaults: Vec<(String, String)>,
+    /// Only use objects of the type from the thread which created them,
+    /// even if it is `Send` and `Sync`.
+    pub unsendable: bool,
+    /// Release the Python GIL while calling into Rust.
+    pub release_gil: bool,
+    /// Return an awaitable for an `async fn`, rather than blocking on it.
+    pub awaitable: bool,
+}
+
+impl SwigOptions {
+    /// Collect the options from any `#[swig(..)]` attributes.
+    pub fn from_attrs(attrs: &[syn::Attribute]) -> Self {
+        let mut opts = SwigOptions::default();
+        let nested = attrs.iter().filter(|a| is_swig_attr(a)).flat_map(|a| {
+            match a.interpret_meta() {
+                Some(syn::Meta::List(ml)) => ml.nested,
+                _ => panic!("Expected options in the form `#[swig(..)]`"),
+            }
+        });
+        for nm in nested {
+            match nm {
+                syn::NestedMeta::Meta(syn::Meta::Word(ref w)) if w == "constructor" => {
+                    opts.constructor = true;
+                },
+                syn::NestedMeta::Meta(syn::Meta::Word(ref w)) if w == "skip" => {
+                    opts.skip = true;
+                },
+                syn::NestedMeta::Meta(syn::Meta::Word(ref w)) if w == "unsendable" => {
+                    opts.unsendable = true;
+                },
+                syn::NestedMeta::Meta(syn::Meta::Word(ref w)) if w == "release_gil" => {
+                    opts.release_gil = true;
+                },
+                syn::NestedMeta::Meta(syn::Meta::Word(ref w)) if w == "awaitable" => {
+                    opts.awaitable = true;
+                },
+                syn::NestedMeta::Meta(syn::Meta::NameValue(ref mnv)) if mnv.ident == "name" => {
+                    match mnv.lit {
+                        syn::Lit::Str(ref ls) => opts.name = Some(ls.value()),
+                        _ => panic!("Expected a string in `#[swig(name = \"..\")]`"),
+                    }
+                },
+                syn::NestedMeta::Meta(syn::Meta::List(ref ml)) if ml.ident == "default" => {
+                    for nm in ml.nested.iter() {
+                        match nm {
+                            syn::NestedMeta::Meta(syn::Meta::NameValue(mnv)) => {
+                                opts.defaults.push((mnv.ident.to_string(), mnv.lit.clone().into_token_stream().to_string()));
+                            },
+                            other => panic!("Expected `name = value` in `#[swig(default(..))]`, got `{}`", other.clone().into_token_stream()),
+                        }
+                    }
+                },
+                other => panic!("Unknown `#[swig(..)]` option: `{}`", other.into_token_stream()),
+            }
+        }
+        opts
+    }
+
+    /// The default value given for a parameter, if any.
+    fn default_of(&self, param: &str) -> Option<&str> {
+        self.defaults.iter().find(|(id, _)| id == param).map(|(_, val)| val.as_str())
+    }
+}
+
+/// Whether this is a `#[swig(..)]` options attribute. On functions these are
+/// only read by `#[swiggen]`, so need removing before it is emitted again.
+pub fn is_swig_attr(attr: &syn::Attribute) -> bool {
+    attr.path.clone().into_token_stream().to_string() == "swig"
+}
+
+/// Arguments to the `#[swiggen]` attribute, e.g.
+/// `#[swiggen(Foo, instantiate(T = u32, T = f64))]`.
+#[derive(Debug, Default)]
+pub struct Args {
+    /// The type a method belongs to.
+    pub base: Option<syn::Ident>,
+    /// Concrete types to export a generic function with.
+    pub instances: Vec<Instance>,
+}
+
+/// A single item in the `#[swiggen(...)]` argument list.
+enum ArgItem {
+    Base(syn::Ident),
+    Instantiate(Instances),
+}
+
+impl syn::synom::Synom for ArgItem {
+    named!(parse -> Self, alt!(
+        do_parse!(
+            custom_keyword!(instantiate) >>
+            insts: syn!(Instances) >>
+            (ArgItem::Instantiate(insts))
+        )
+        |
+        syn!(syn::Ident) => { ArgItem::Base }
+    ));
+}
 
-// Extract an `Option<Ident>` from `(T)` or `""`.
+// Extract the `Args` from `(Foo, instantiate(..))` as in an attribute's
+// `tts`, or from the bare `Foo, instantiate(..)` a proc macro attribute is
+// passed, which may be empty.
 impl syn::synom::Synom for Args {
-    named!(parse -> Self, map!(option!(map!(
-        parens!(syn!(syn::Ident)),
-        |(_parens, id)| id
-    )), |o| Args(o)));
+    named!(parse -> Self, map!(alt!(
+        map!(
+            parens!(call!(syn::punctuated::Punctuated::<ArgItem, Token![,]>::parse_terminated)),
+            |(_parens, items)| items
+        )
+        |
+        call!(syn::punctuated::Punctuated::<ArgItem, Token![,]>::parse_terminated)
+    ), |items| {
+        let mut args = Args::default();
+        for item in items {
+            match item {
+                ArgItem::Base(id) => args.base = Some(id),
+                ArgItem::Instantiate(insts) => args.instances.extend(insts.0),
+            }
+        }
+        args
+    }));
+}
+
+/// One set of concrete types to export a generic item with, e.g. `T = u32`
+/// or `(K = u32, V = f64)`.
+#[derive(Clone, Debug)]
+pub struct Instance(pub Vec<(syn::Ident, syn::Type)>);
+
+/// A single `T = u32` binding.
+struct Binding(syn::Ident, syn::Type);
+
+impl syn::synom::Synom for Binding {
+    named!(parse -> Self, do_parse!(
+        id: syn!(syn::Ident) >>
+        punct!(=) >>
+        ty: syn!(syn::Type) >>
+        (Binding(id, ty))
+    ));
+}
+
+impl syn::synom::Synom for Instance {
+    named!(parse -> Self, alt!(
+        parens!(call!(syn::punctuated::Punctuated::<Binding, Token![,]>::parse_terminated)) => {
+            |(_parens, bs)| Instance(bs.into_iter().map(|b| (b.0, b.1)).collect())
+        }
+        |
+        syn!(Binding) => { |b| Instance(vec![(b.0, b.1)]) }
+    ));
+}
+
+/// A parenthesised list of instances: `(T = u32, T = f64)`.
+#[derive(Clone, Debug)]
+pub struct Instances(pub Vec<Instance>);
+
+impl syn::synom::Synom for Instances {
+    named!(parse -> Self, map!(
+        parens!(call!(syn::punctuated::Punctuated::<Instance, Token![,]>::parse_terminated)),
+        |(_parens, insts)| Instances(insts.into_iter().collect())
+    ));
+}
+
+impl Instance {
+    /// Look up the type bound to a generic parameter.
+    fn get(&self, param: &syn::Ident) -> Option<&syn::Type> {
+        self.0.iter().find(|(id, _)| id == param).map(|(_, ty)| ty)
+    }
+
+    /// The same bindings, with those of `params` first and in that order,
+    /// so that they can be listed in any order.
+    fn ordered(&self, params: &[syn::Ident]) -> Instance {
+        let mut bindings = self.0.clone();
+        bindings.sort_by_key(|(id, _)| params.iter().position(|p| p == id).unwrap_or(params.len()));
+        Instance(bindings)
+    }
+}
+
+/// syn doesn't know about `async fn` yet, so keeps them as verbatim items.
+/// Parse one as a plain function (or method) by leaving out the `async`,
+/// which `add_async` puts back. Only an `async` right after the attributes
+/// and visibility is taken as part of the header.
+pub fn parse_async_fn<T: syn::synom::Synom>(tts: &TokenStream) -> Option<T> {
+    let mut tokens: Vec<TokenTree> = tts.clone().into_iter().collect();
+    let pos = fn_header_start(&tokens);
+    if !is_ident(tokens.get(pos), "async") {
+        return None;
+    }
+    if !is_fn_keyword(tokens.get(pos + 1)) {
+        panic!("Expected `fn` after `async` in `{}`", tts);
+    }
+    tokens.remove(pos);
+    match syn::parse2(tokens.into_iter().collect()) {
+        Ok(item) => Some(item),
+        Err(e) => panic!("Cannot parse `{}` as an `async fn`: {}", tts, e),
+    }
+}
+
+/// Emit a function parsed by `parse_async_fn` as an `async fn` again.
+pub fn add_async<T: ToTokens>(item: &T) -> TokenStream {
+    let mut tokens: Vec<TokenTree> = item.into_token_stream().into_iter().collect();
+    let pos = fn_header_start(&tokens);
+    if !is_fn_keyword(tokens.get(pos)) {
+        panic!("Expected a function, found `{}`", item.into_token_stream());
+    }
+    // Spanned like the `fn`, so it takes the edition of the user's crate.
+    let span = tokens[pos].span();
+    tokens.insert(pos, TokenTree::Ident(syn::Ident::new("async", span)));
+    tokens.into_iter().collect()
+}
+
+/// Where the header of a function starts, after its attributes and
+/// visibility (`pub`, `pub(..)` or `crate`).
+fn fn_header_start(tokens: &[TokenTree]) -> usize {
+    let mut pos = 0;
+    loop {
+        match (tokens.get(pos), tokens.get(pos + 1)) {
+            (Some(TokenTree::Punct(p)), Some(TokenTree::Group(g)))
+                if p.as_char() == '#' && g.delimiter() == proc_macro2::Delimiter::Bracket => pos += 2,
+            _ => break,
+        }
+    }
+    if is_ident(tokens.get(pos), "pub") {
+        pos += 1;
+        if let Some(TokenTree::Group(g)) = tokens.get(pos) {
+            if g.delimiter() == proc_macro2::Delimiter::Parenthesis {
+                pos += 1;
+            }
+        }
+    } else if is_ident(tokens.get(pos), "crate") {
+        pos += 1;
+    }
+    pos
+}
+
+/// Whether this starts the rest of a function header, e.g. `unsafe fn`.
+fn is_fn_keyword(tt: Option<&TokenTree>) -> bool {
+    ["fn", "unsafe", "extern"].iter().any(|kw| is_ident(tt, kw))
+}
+
+fn is_ident(tt: Option<&TokenTree>, name: &str) -> bool {
+    match tt {
+        Some(TokenTree::Ident(id)) => id == name,
+        _ => false,
+    }
 }
 
 fn iim_to_itemfn(iim: syn::ImplItemMethod) -> syn::ItemFn {
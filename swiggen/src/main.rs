@@ -29,6 +29,21 @@ pub struct Manifest {
 #[derive(Clone, Deserialize, Debug)]
 pub struct Package {
     pub name: String,
+    #[serde(default)]
+    pub metadata: Metadata,
+}
+
+#[derive(Clone, Deserialize, Debug, Default)]
+pub struct Metadata {
+    #[serde(default)]
+    pub swiggen: SwiggenConfig,
+}
+
+/// Options given in `[package.metadata.swiggen]`.
+#[derive(Clone, Deserialize, Debug, Default)]
+pub struct SwiggenConfig {
+    /// Layout of nested modules in Python: `"flat"`, `"prefix"` or `"submodules"`.
+    pub python_modules: Option<String>,
 }
 
 /// Parse the Cargo.toml for a given path
@@ -45,6 +60,15 @@ pub fn main() {
 
     trace!("{:#?}", manifest);
     let package_name = &manifest.package.name.replace("-", "_");
+    // The environment takes precedence, e.g. to test each layout.
+    let py_modules = env::var("SWIGGEN_PYTHON_MODULES").ok()
+        .or_else(|| manifest.package.metadata.swiggen.python_modules.clone());
+    let py_modules = match py_modules.as_ref().map(|s| s.as_str()) {
+        None | Some("flat") => swiggen::PyModules::Flat,
+        Some("prefix") => swiggen::PyModules::Prefix,
+        Some("submodules") => swiggen::PyModules::Submodules,
+        Some(other) => panic!("Unknown `python_modules = \"{}\"`, expected \"flat\", \"prefix\" or \"submodules\"", other),
+    };
 
     let tmp_dir = TempDir::new("cargo-exp").unwrap();
     let file_path = tmp_dir.path().join("expanded.rs");
@@ -54,13 +78,17 @@ pub fn main() {
     
     let mut cmd = Command::new(cargo);
     cmd.arg("expand");
-    cmd.arg("--features=bindings");
+    // Extra features can be given, e.g. to test each option of the prelude.
+    match env::var("SWIGGEN_FEATURES") {
+        Ok(extra) => cmd.arg(format!("--features=bindings,{}", extra)),
+        Err(_) => cmd.arg("--features=bindings"),
+    };
     let output = cmd.output().unwrap();
     trace!("Output: {:#?}", output);
     tmp_file.write_all(&output.stdout).unwrap();
 
     gen_bindings(&file_path);
-    swiggen::gen_swig(package_name, str::from_utf8(&output.stdout).unwrap());
+    swiggen::gen_swig(package_name, str::from_utf8(&output.stdout).unwrap(), py_modules);
 }
 
 fn gen_bindings(path: &Path) {